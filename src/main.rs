@@ -1,8 +1,13 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::env;
+use std::fmt;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::num::IntErrorKind;
+use std::time::{Duration, Instant};
 
-fn file_size(file: &mut File) -> usize {
+fn file_size<S: Seek>(file: &mut S) -> u64 {
     let original = match file.stream_position() {
         Ok(pos) => pos,
         Err(e) => {
@@ -17,7 +22,13 @@ fn file_size(file: &mut File) -> usize {
     if let Err(e) = file.seek(SeekFrom::Start(original)) {
         println!("fsetpos() failed: {}", e.raw_os_error().unwrap_or(0));
     }
-    out as usize
+    out
+}
+
+/// Size of the buffer needed to hold SIZE bytes of file contents plus the
+/// terminating NUL, or None if that does not fit in memory on this target.
+fn contents_buffer_size(size: u64) -> Option<usize> {
+    usize::try_from(size).ok()?.checked_add(1)
 }
 
 fn file_contents(path: &str) -> Option<Vec<u8>> {
@@ -28,8 +39,27 @@ fn file_contents(path: &str) -> Option<Vec<u8>> {
             return None;
         }
     };
-    let size = file_size(&mut file);
-    let mut contents = vec![0u8; size + 1];
+    // Pipes and other streams can not be sized by seeking, so they are read
+    // to the end instead.
+    if !file.metadata().is_ok_and(|metadata| metadata.is_file()) {
+        let mut contents = Vec::new();
+        if let Err(e) = file.read_to_end(&mut contents) {
+            println!("Error while reading: {}", e.raw_os_error().unwrap_or(0));
+            return None;
+        }
+        contents.push(0);
+        return Some(contents);
+    }
+    let file_bytes = file_size(&mut file);
+    let Some(buffer_size) = contents_buffer_size(file_bytes) else {
+        println!(
+            "File at {} is too large to read ({} bytes)",
+            path, file_bytes
+        );
+        return None;
+    };
+    let size = buffer_size - 1;
+    let mut contents = vec![0u8; buffer_size];
     assert!(
         !contents.is_empty() || size == 0,
         "Could not allocate buffer for file contents"
@@ -50,15 +80,163 @@ fn file_contents(path: &str) -> Option<Vec<u8>> {
             break;
         }
     }
+    // The file may have shrunk since it was sized; every byte before the
+    // NUL is one that was read.
+    contents.truncate(bytes_read + 1);
     contents[bytes_read] = 0;
     Some(contents)
 }
 
+/// Encodings that source files may be read in.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    ENCODING_UTF8,
+    ENCODING_LATIN1,
+}
+
+/// Transcode Latin-1 (ISO-8859-1) BYTES to UTF-8. Every byte is exactly
+/// one character, so this can not fail.
+fn latin1_to_utf8(bytes: &[u8]) -> Vec<u8> {
+    let mut utf8 = String::with_capacity(bytes.len());
+    for &c in bytes {
+        utf8.push(c as char);
+    }
+    utf8.into_bytes()
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+const PROT_READ: i32 = 1;
+#[cfg(all(unix, feature = "mmap"))]
+const MAP_PRIVATE: i32 = 2;
+
+#[cfg(all(unix, feature = "mmap"))]
+unsafe extern "C" {
+    fn mmap(
+        addr: *mut std::ffi::c_void,
+        length: usize,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: std::ffi::c_long,
+    ) -> *mut std::ffi::c_void;
+    fn munmap(addr: *mut std::ffi::c_void, length: usize) -> i32;
+}
+
+/// A read-only, private mapping of an entire file.
+#[cfg(all(unix, feature = "mmap"))]
+struct MappedFile {
+    address: *mut std::ffi::c_void,
+    length: usize,
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl MappedFile {
+    fn bytes(&self) -> &[u8] {
+        // SAFETY: The mapping is readable for LENGTH bytes and lives as long
+        // as SELF does.
+        unsafe { std::slice::from_raw_parts(self.address as *const u8, self.length) }
+    }
+}
+
+#[cfg(all(unix, feature = "mmap"))]
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        // SAFETY: ADDRESS and LENGTH came from a successful mmap() call.
+        unsafe {
+            munmap(self.address, self.length);
+        }
+    }
+}
+
+/// Map the file at PATH into memory instead of copying it into a buffer.
+/// Returns None when the file can not be mapped (empty files, pipes,
+/// etc.), in which case the caller should fall back to file_contents().
+#[cfg(all(unix, feature = "mmap"))]
+fn file_map(path: &str) -> Option<MappedFile> {
+    use std::os::fd::AsRawFd;
+
+    let file = File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+    if !metadata.is_file() {
+        return None;
+    }
+    let length = usize::try_from(metadata.len()).ok()?;
+    if length == 0 {
+        return None;
+    }
+    // SAFETY: A fresh private read-only mapping; the file descriptor may be
+    // closed once mmap() returns.
+    let address = unsafe {
+        mmap(
+            std::ptr::null_mut(),
+            length,
+            PROT_READ,
+            MAP_PRIVATE,
+            file.as_raw_fd(),
+            0,
+        )
+    };
+    if address as isize == -1 {
+        return None;
+    }
+    Some(MappedFile { address, length })
+}
+
+/// Exit with an error if RESULT, from writing output, is a failure.
+fn check_written(result: io::Result<()>) {
+    if let Err(e) = result {
+        eprintln!("Error while writing: {}", e);
+        std::process::exit(1);
+    }
+}
+
 fn print_usage(argv0: &str) {
-    println!("USAGE: {} <path_to_file_to_compile>", argv0);
+    println!("USAGE: {} [OPTIONS] <path_to_file_to_compile>...", argv0);
+    println!("OPTIONS:");
+    println!("  -o FILE");
+    println!("      Write output to FILE instead of stdout; errors are not included.");
+    println!("  --overflow checked|wrapping|saturating");
+    println!("      How integer literals that overflow are handled (default: checked).");
+    println!("  --encoding utf8|latin1");
+    println!("      Encoding of the source file (default: utf8).");
+    println!("  --tab-width N");
+    println!("      Columns per tab stop when reporting positions (default: 1).");
+    println!("  --max-token-length N");
+    println!("      Reject tokens longer than N bytes (default: 1048576).");
+    println!("  --max-errors N");
+    println!("      With --check, report at most N errors per file (default: 20).");
+    println!("  --ast-depth-limit N");
+    println!("      Reject ASTs nested more than N levels deep.");
+    println!("  --error-format human|json");
+    println!("      Print errors as prose (default) or as JSON Lines on stderr.");
+    println!("  --check");
+    println!("      Only report errors; exit with status 1 if any file has one.");
+    println!("  --single");
+    println!("      Require the file to hold exactly one expression, printed bare.");
+    println!("  --count-tokens");
+    println!("      Only lex the file and print the number of tokens.");
+    println!("  --trace-lex");
+    println!("      Print every token and its position to stderr before parsing.");
+    println!("  --stats-json");
+    println!("      After a successful compile, print only timings and counts as JSON.");
+    println!("  --verify-lex");
+    println!("      Check that the tokens cover the file with no gaps or overlaps.");
+    println!("  --dump-ast[=tree|json|dot|canonical]");
+    println!("      Format of the printed AST (default: tree).");
+    println!("  --int-base dec|hex|bin|oct");
+    println!("      Base integers are printed in, except in JSON (default: dec).");
+    println!("  --dump-env");
+    println!("      Print the type and variable environments after parsing.");
+    println!("  --sort-env");
+    println!("      Sort --dump-env output by name instead of storage order.");
+    println!("  --strict");
+    println!("      Reject loose syntax, such as a bare integer at the top level.");
+    println!("  --forbid-redefinition");
+    println!("      Make declaring an already declared variable an error.");
 }
 
-// TODO: Add file path, byte offset, etc.
+// TODO: Add file path.
 #[allow(non_camel_case_types)]
 #[repr(i32)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -67,21 +245,75 @@ enum ErrorType {
     ERROR_ARGUMENTS,
     ERROR_TYPE,
     ERROR_GENERIC,
+    ERROR_LEX,
     ERROR_SYNTAX,
     ERROR_TODO,
-    ERROR_MAX,
+}
+
+/// A region of source: the byte range [BEGINNING, END), along with the
+/// 1-based line and column at which it begins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Span {
+    beginning: usize,
+    end: usize,
+    line: usize,
+    column: usize,
+}
+
+/// Span of token T within SOURCE.
+fn span_create(source: &[u8], t: &Token, tab_width: usize) -> Span {
+    let (line, column) = offset_to_line_col(source, t.beginning, tab_width);
+    Span {
+        beginning: t.beginning,
+        end: t.end,
+        line,
+        column,
+    }
 }
 
 #[derive(Clone, Debug)]
 struct Error {
     type_: ErrorType,
     msg: Option<String>,
+    /// Where in the source the error was found, if anywhere.
+    span: Option<Span>,
 }
 
 fn ok() -> Error {
     Error {
         type_: ErrorType::ERROR_NONE,
         msg: None,
+        span: None,
+    }
+}
+
+impl ErrorType {
+    /// Fixed text printed for this kind of error. ERROR_NONE and
+    /// ERROR_GENERIC have none; for those the message is all there is.
+    fn describe(&self) -> &'static str {
+        match self {
+            ErrorType::ERROR_NONE => "",
+            ErrorType::ERROR_ARGUMENTS => "Invalid arguments",
+            ErrorType::ERROR_TYPE => "Mismatched types",
+            ErrorType::ERROR_GENERIC => "",
+            ErrorType::ERROR_LEX => "Lexical error",
+            ErrorType::ERROR_SYNTAX => "Syntax error",
+            ErrorType::ERROR_TODO => "TODO (not implemented)",
+        }
+    }
+
+    /// Name of this kind of error as it appears in machine-readable
+    /// error output.
+    fn name(&self) -> &'static str {
+        match self {
+            ErrorType::ERROR_NONE => "NONE",
+            ErrorType::ERROR_ARGUMENTS => "ARGUMENTS",
+            ErrorType::ERROR_TYPE => "TYPE",
+            ErrorType::ERROR_GENERIC => "GENERIC",
+            ErrorType::ERROR_LEX => "LEX",
+            ErrorType::ERROR_SYNTAX => "SYNTAX",
+            ErrorType::ERROR_TODO => "TODO",
+        }
     }
 }
 
@@ -89,51 +321,154 @@ fn print_error(err: &Error) {
     if err.type_ == ErrorType::ERROR_NONE {
         return;
     }
-    print!("ERROR: ");
-    debug_assert_eq!(ErrorType::ERROR_MAX as i32, 6);
-    match err.type_ {
-        ErrorType::ERROR_TODO => print!("TODO (not implemented)"),
-        ErrorType::ERROR_SYNTAX => print!("Invalid syntax"),
-        ErrorType::ERROR_TYPE => print!("Mismatched types"),
-        ErrorType::ERROR_ARGUMENTS => print!("Invalid arguments"),
-        ErrorType::ERROR_GENERIC => {}
-        ErrorType::ERROR_NONE => {}
-        _ => print!("Unkown error type..."),
-    }
-    println!();
+    print!("ERROR");
+    if let Some(span) = &err.span {
+        print!(" at {}:{}", span.line, span.column);
+    }
+    println!(": {}", err.type_.describe());
     if let Some(msg) = &err.msg {
         println!("     : {}", msg);
     }
 }
 
+/// Ways of reporting errors found in source files.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ErrorFormat {
+    ERROR_FORMAT_HUMAN,
+    ERROR_FORMAT_JSON,
+}
+
+/// ERR as a single-line JSON object naming PATH as the file it was found
+/// in. Every key is always present, null when there is nothing to say.
+fn error_json(err: &Error, path: Option<&str>) -> String {
+    let mut json = format!("{{\"kind\":\"{}\"", err.type_.name());
+    json.push_str(&format!(
+        ",\"message\":\"{}\"",
+        escaped(err.msg.as_deref().unwrap_or(""))
+    ));
+    match &err.span {
+        Some(span) => json.push_str(&format!(
+            ",\"line\":{},\"column\":{},\"span\":[{},{}]",
+            span.line, span.column, span.beginning, span.end
+        )),
+        None => json.push_str(",\"line\":null,\"column\":null,\"span\":null"),
+    }
+    match path {
+        Some(path) => json.push_str(&format!(",\"file\":\"{}\"", escaped(path))),
+        None => json.push_str(",\"file\":null"),
+    }
+    json.push('}');
+    json
+}
+
+/// Print ERR, if there is one, as JSON on stderr; see error_json().
+fn print_error_json(err: &Error, path: Option<&str>) {
+    if err.type_ == ErrorType::ERROR_NONE {
+        return;
+    }
+    eprintln!("{}", error_json(err, path));
+}
+
+/// Report ERR found in the file at PATH, if any, in FORMAT.
+fn report_error(err: &Error, format: ErrorFormat, path: Option<&str>) {
+    match format {
+        ErrorFormat::ERROR_FORMAT_HUMAN => print_error(err),
+        ErrorFormat::ERROR_FORMAT_JSON => print_error_json(err, path),
+    }
+}
+
 fn error_prep(err: &mut Error, kind: ErrorType, message: &str) {
     err.type_ = kind;
     err.msg = Some(message.to_string());
 }
 
-const WHITESPACE: &[u8] = b" \r\n";
-const DELIMITERS: &[u8] = b" \r\n,():";
+/// Like error_prep(), but also record SPAN as the error's location.
+fn error_prep_at(err: &mut Error, kind: ErrorType, message: &str, span: Span) {
+    error_prep(err, kind, message);
+    err.span = Some(span);
+}
+
+const WHITESPACE: &[u8] = b" \t\r\n";
+const DELIMITERS: &[u8] = b" \t\r\n,():";
 
+/// lex() keeps no state of its own, so a token is a complete lexer
+/// position: copying one is an O(1) checkpoint, and lexing again from a
+/// saved copy rewinds to it.
+#[derive(Clone, Copy, Debug)]
 struct Token {
     beginning: usize,
     end: usize,
 }
 
-fn print_token(source: &[u8], t: &Token) {
+/// Point TEXT at the bytes of token T within SOURCE.
+/// Fails with a syntax error, rather than masking the bytes, if the
+/// token is not valid UTF-8. The caller attaches the error's span.
+fn token_text<'a>(source: &'a [u8], t: &Token, text: &mut &'a str) -> Error {
+    let mut err = ok();
     if t.beginning >= t.end || t.end > source.len() {
-        return;
+        *text = "";
+        return err;
+    }
+    match std::str::from_utf8(&source[t.beginning..t.end]) {
+        Ok(string) => *text = string,
+        Err(_) => error_prep(&mut err, ErrorType::ERROR_SYNTAX, "Invalid UTF-8 in token"),
+    }
+    err
+}
+
+/// Convert a byte OFFSET into SOURCE to a 1-based (line, column) pair.
+/// OFFSET may point one past the last byte, i.e. at end of input.
+/// Columns count characters (Unicode scalar values), not bytes, so they
+/// line up with what an editor shows for UTF-8 source. A tab advances
+/// to the next multiple of TAB_WIDTH columns.
+fn offset_to_line_col(source: &[u8], offset: usize, tab_width: usize) -> (usize, usize) {
+    let mut line = 1;
+    let mut column = 1;
+    for &c in &source[..offset.min(source.len())] {
+        if c == b'\n' {
+            line += 1;
+            column = 1;
+        } else if c == b'\t' {
+            column = (column - 1) / tab_width * tab_width + tab_width + 1;
+        } else if c & 0xC0 != 0x80 {
+            // UTF-8 continuation bytes do not start a new character.
+            column += 1;
+        }
+    }
+    (line, column)
+}
+
+/// Fail with a syntax error at token T if it is longer than CONTEXT allows.
+fn check_token_length(context: &ParsingContext, source: &[u8], t: &Token) -> Error {
+    let mut err = ok();
+    if t.end.saturating_sub(t.beginning) > context.max_token_length {
+        error_prep_at(
+            &mut err,
+            ErrorType::ERROR_SYNTAX,
+            "Token too long",
+            span_create(source, t, context.tab_width),
+        );
     }
-    print!("{}", String::from_utf8_lossy(&source[t.beginning..t.end]));
+    err
 }
 
 /// Lex the next token from SOURCE, and point to it with BEG and END.
-fn lex(source: &[u8], start: usize, token: &mut Token) -> Error {
+/// A token longer than CONTEXT allows is an error, found without
+/// scanning more than one byte past the limit.
+fn lex(context: &ParsingContext, source: &[u8], start: usize, token: &mut Token) -> Error {
     let mut err = ok();
     if start > source.len() {
-        error_prep(&mut err, ErrorType::ERROR_ARGUMENTS, "Can not lex empty source.");
+        error_prep(&mut err, ErrorType::ERROR_LEX, "Can not lex empty source.");
         return err;
     }
     token.beginning = start;
+    // A "#!" line at the very start of the file is a shebang, not source.
+    if start == 0 && source.starts_with(b"#!") {
+        while token.beginning < source.len() && source[token.beginning] != b'\n' {
+            token.beginning += 1;
+        }
+    }
     while token.beginning < source.len() && WHITESPACE.contains(&source[token.beginning]) {
         token.beginning += 1;
     }
@@ -144,15 +479,187 @@ fn lex(source: &[u8], start: usize, token: &mut Token) -> Error {
     if source[token.end] == 0 {
         return err;
     }
+    // A pipe-escaped symbol, like |a,b|, runs to the closing pipe no
+    // matter what delimiters it contains.
+    if source[token.end] == b'|' {
+        token.end += 1;
+        while token.end < source.len()
+            && source[token.end] != b'|'
+            && source[token.end] != 0
+            && token.end - token.beginning <= context.max_token_length
+        {
+            token.end += 1;
+        }
+        err = check_token_length(context, source, token);
+        if err.type_ != ErrorType::ERROR_NONE {
+            return err;
+        }
+        if token.end >= source.len() || source[token.end] != b'|' {
+            error_prep_at(
+                &mut err,
+                ErrorType::ERROR_LEX,
+                "Unterminated pipe-escaped symbol",
+                span_create(source, token, context.tab_width),
+            );
+            return err;
+        }
+        token.end += 1;
+        // Symbols are never empty, escaped or not.
+        if token.end - token.beginning == 2 {
+            error_prep_at(
+                &mut err,
+                ErrorType::ERROR_LEX,
+                "Empty pipe-escaped symbol",
+                span_create(source, token, context.tab_width),
+            );
+            return err;
+        }
+        return check_token_length(context, source, token);
+    }
     while token.end < source.len()
         && !DELIMITERS.contains(&source[token.end])
         && source[token.end] != 0
+        && token.end - token.beginning <= context.max_token_length
     {
         token.end += 1;
     }
     if token.end == token.beginning {
         token.end += 1;
     }
+    check_token_length(context, source, token)
+}
+
+/// Name of the symbol spelled by token TEXT, without the pipes of a
+/// pipe-escaped symbol.
+fn symbol_name(text: &str) -> &str {
+    if text.len() >= 2 && text.starts_with('|') && text.ends_with('|') {
+        return &text[1..text.len() - 1];
+    }
+    text
+}
+
+/// Lex all of SOURCE without parsing it, storing the number of tokens
+/// in COUNT.
+fn count_tokens(context: &ParsingContext, source: &[u8], count: &mut usize) -> Error {
+    let mut token = Token {
+        beginning: 0,
+        end: 0,
+    };
+    *count = 0;
+    loop {
+        let err = lex(context, source, token.end, &mut token);
+        if err.type_ != ErrorType::ERROR_NONE {
+            return err;
+        }
+        if token.end == token.beginning {
+            break;
+        }
+        *count += 1;
+    }
+    ok()
+}
+
+/// Print every token in SOURCE to OUT, one per line, after the line and
+/// column it begins at. Stops quietly at a lexical error, which the
+/// parser goes on to report.
+fn trace_tokens(out: &mut dyn Write, context: &ParsingContext, source: &[u8]) -> io::Result<()> {
+    let mut token = Token {
+        beginning: 0,
+        end: 0,
+    };
+    loop {
+        let err = lex(context, source, token.end, &mut token);
+        if err.type_ != ErrorType::ERROR_NONE || token.end == token.beginning {
+            return Ok(());
+        }
+        let mut text = "";
+        if token_text(source, &token, &mut text).type_ != ErrorType::ERROR_NONE {
+            return Ok(());
+        }
+        let (line, column) = offset_to_line_col(source, token.beginning, context.tab_width);
+        writeln!(out, "lexed {}:{}: {}", line, column, text)?;
+    }
+}
+
+/// Lex all of SOURCE, appending every token to TOKENS.
+fn lex_tokens(context: &ParsingContext, source: &[u8], tokens: &mut Vec<Token>) -> Error {
+    let mut token = Token {
+        beginning: 0,
+        end: 0,
+    };
+    loop {
+        let err = lex(context, source, token.end, &mut token);
+        if err.type_ != ErrorType::ERROR_NONE {
+            return err;
+        }
+        if token.end == token.beginning {
+            break;
+        }
+        tokens.push(token);
+    }
+    ok()
+}
+
+/// Check that TOKENS, in order, cover every byte of SOURCE that lex()
+/// does not skip. Only whitespace may lie between tokens, besides a
+/// shebang line at the start and anything from the first NUL on. Fails
+/// with a lexical error at the first empty or overlapping token, or at
+/// the first byte no token covers.
+fn verify_token_coverage(source: &[u8], tokens: &[Token]) -> Error {
+    let mut err = ok();
+    let mut cursor = 0usize;
+    if source.starts_with(b"#!") {
+        while cursor < source.len() && source[cursor] != b'\n' {
+            cursor += 1;
+        }
+    }
+    let uncovered = |from: usize, to: usize| {
+        source[from..to]
+            .iter()
+            .position(|c| !WHITESPACE.contains(c))
+            .map(|gap| from + gap)
+    };
+    for (index, token) in tokens.iter().enumerate() {
+        if token.end <= token.beginning || token.end > source.len() {
+            error_prep(
+                &mut err,
+                ErrorType::ERROR_LEX,
+                &format!("Token {} is empty or out of bounds", index),
+            );
+            return err;
+        }
+        if token.beginning < cursor {
+            error_prep(
+                &mut err,
+                ErrorType::ERROR_LEX,
+                &format!(
+                    "Token {} at byte {} overlaps what came before it",
+                    index, token.beginning
+                ),
+            );
+            return err;
+        }
+        if let Some(offset) = uncovered(cursor, token.beginning) {
+            error_prep(
+                &mut err,
+                ErrorType::ERROR_LEX,
+                &format!("Byte {} is not covered by any token", offset),
+            );
+            return err;
+        }
+        cursor = token.end;
+    }
+    let rest = source[cursor..]
+        .iter()
+        .position(|&c| c == 0)
+        .map_or(source.len(), |nul| cursor + nul);
+    if let Some(offset) = uncovered(cursor, rest) {
+        error_prep(
+            &mut err,
+            ErrorType::ERROR_LEX,
+            &format!("Byte {} is not covered by any token", offset),
+        );
+    }
     err
 }
 
@@ -190,6 +697,10 @@ enum NodeType {
     /// Just an integer.
     NODE_TYPE_INTEGER,
 
+    /// An exact fraction, kept in lowest terms with a positive
+    /// denominator.
+    NODE_TYPE_RATIONAL,
+
     /// When a literal is expected but no other literal is valid, it
     /// becomes a symbol.
     NODE_TYPE_SYMBOL,
@@ -208,12 +719,18 @@ enum NodeType {
     /// Contains a list of expressions to execute in sequence.
     NODE_TYPE_PROGRAM,
 
+    /// Stands in for an expression that failed to parse, wherever
+    /// parse_all() had to recover from an error.
+    NODE_TYPE_ERROR,
+
     NODE_TYPE_MAX,
 }
 
 #[derive(Clone, Debug)]
 struct NodeValue {
+    /// Also the numerator of a rational.
     integer: integer_t,
+    denominator: integer_t,
     symbol: Option<String>,
 }
 
@@ -232,6 +749,7 @@ fn node_allocate() -> Box<Node> {
         type_: NodeType::NODE_TYPE_NONE,
         value: NodeValue {
             integer: 0,
+            denominator: 1,
             symbol: None,
         },
         children: None,
@@ -247,6 +765,10 @@ fn integerp(node: &Node) -> bool {
     node.type_ == NodeType::NODE_TYPE_INTEGER
 }
 
+fn rationalp(node: &Node) -> bool {
+    node.type_ == NodeType::NODE_TYPE_RATIONAL
+}
+
 fn symbolp(node: &Node) -> bool {
     node.type_ == NodeType::NODE_TYPE_SYMBOL
 }
@@ -268,6 +790,55 @@ fn node_add_child(parent: &mut Node, new_child: Box<Node>) {
     }
 }
 
+/// Move the children of every PROGRAM node in PROGRAMS, in order, into
+/// RESULT, which becomes a PROGRAM node. Stops with a type error at the
+/// first input that is not a program, keeping what was merged so far.
+fn merge_programs(programs: Vec<Node>, result: &mut Node) -> Error {
+    let mut err = ok();
+    result.type_ = NodeType::NODE_TYPE_PROGRAM;
+    for mut program in programs {
+        if program.type_ != NodeType::NODE_TYPE_PROGRAM {
+            error_prep(
+                &mut err,
+                ErrorType::ERROR_TYPE,
+                "Only program nodes can be merged",
+            );
+            return err;
+        }
+        let mut cursor = program.children.take();
+        while let Some(mut child) = cursor {
+            cursor = child.next_child.take();
+            node_add_child(result, child);
+        }
+    }
+    err
+}
+
+/// Fail if the tree rooted at NODE, which is at depth 1, is more than
+/// LIMIT levels deep. The tree is walked without recursion, so this is
+/// safe to run on trees too deep for the recursive passes it protects.
+fn check_ast_depth(node: &Node, limit: usize) -> Error {
+    let mut err = ok();
+    let mut depth = 0usize;
+    let mut stack = vec![(node, 1usize)];
+    while let Some((node, node_depth)) = stack.pop() {
+        depth = depth.max(node_depth);
+        let mut child = node.children.as_deref();
+        while let Some(child_node) = child {
+            stack.push((child_node, node_depth + 1));
+            child = child_node.next_child.as_deref();
+        }
+    }
+    if depth > limit {
+        error_prep(
+            &mut err,
+            ErrorType::ERROR_GENERIC,
+            &format!("AST is {} levels deep, over the limit of {}", depth, limit),
+        );
+    }
+    err
+}
+
 /// @return Boolean-like value; 1 for success, 0 for failure.
 fn node_compare(a: Option<&Node>, b: Option<&Node>) -> i32 {
     if a.is_none() || b.is_none() {
@@ -279,7 +850,7 @@ fn node_compare(a: Option<&Node>, b: Option<&Node>) -> i32 {
     let a = a.unwrap();
     let b = b.unwrap();
     // TODO: This assert doesn't work, I don't know why :^(.
-    debug_assert_eq!(NodeType::NODE_TYPE_MAX as i32, 7, "node_compare() must handle all node types");
+    debug_assert_eq!(NodeType::NODE_TYPE_MAX as i32, 9, "node_compare() must handle all node types");
     if a.type_ != b.type_ {
         return 0;
     }
@@ -296,6 +867,12 @@ fn node_compare(a: Option<&Node>, b: Option<&Node>) -> i32 {
             }
             0
         }
+        NodeType::NODE_TYPE_RATIONAL => {
+            if a.value.integer == b.value.integer && a.value.denominator == b.value.denominator {
+                return 1;
+            }
+            0
+        }
         NodeType::NODE_TYPE_SYMBOL => {
             match (&a.value.symbol, &b.value.symbol) {
                 (Some(left), Some(right)) => {
@@ -326,6 +903,9 @@ fn node_compare(a: Option<&Node>, b: Option<&Node>) -> i32 {
             println!("TODO: Compare two programs.");
             0
         }
+        // Nothing is known about what failed to parse, so two holes are
+        // never the same.
+        NodeType::NODE_TYPE_ERROR => 0,
         NodeType::NODE_TYPE_MAX => 0,
     }
 }
@@ -339,6 +919,37 @@ fn node_integer(value: i64) -> Box<Node> {
     integer
 }
 
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Create a rational node for NUMERATOR/DENOMINATOR in lowest terms.
+/// @return None if DENOMINATOR is zero, or if moving the sign to the
+/// numerator does not fit in an integer.
+fn node_rational(numerator: integer_t, denominator: integer_t) -> Option<Box<Node>> {
+    if denominator == 0 {
+        return None;
+    }
+    // Zero has the one form 0/1, whatever the denominator.
+    let denominator = if numerator == 0 { 1 } else { denominator };
+    let divisor = gcd(numerator.unsigned_abs(), denominator.unsigned_abs());
+    // With zero out of the way, DIVISOR only exceeds integer_t::MAX when
+    // both parts are MIN, which is 1/1.
+    let (mut numerator, mut denominator) = match integer_t::try_from(divisor) {
+        Ok(divisor) => (numerator / divisor, denominator / divisor),
+        Err(_) => (1, 1),
+    };
+    if denominator < 0 {
+        numerator = numerator.checked_neg()?;
+        denominator = denominator.checked_neg()?;
+    }
+    let mut rational = node_allocate();
+    rational.type_ = NodeType::NODE_TYPE_RATIONAL;
+    rational.value.integer = numerator;
+    rational.value.denominator = denominator;
+    Some(rational)
+}
+
 // TODO: Think about caching used symbols and not creating duplicates!
 fn node_symbol(symbol_string: &str) -> Box<Node> {
     let mut symbol = node_allocate();
@@ -347,53 +958,293 @@ fn node_symbol(symbol_string: &str) -> Box<Node> {
     symbol
 }
 
-fn node_symbol_from_buffer(buffer: &[u8]) -> Box<Node> {
-    assert!(!buffer.is_empty(), "Can not create AST symbol node from NULL buffer");
-    let symbol_string = String::from_utf8_lossy(buffer).to_string();
-    let mut symbol = node_allocate();
-    symbol.type_ = NodeType::NODE_TYPE_SYMBOL;
-    symbol.value.symbol = Some(symbol_string);
-    symbol
+/// Render the value of a literal node: NONE as `nil`, an integer in
+/// decimal, a rational as `numerator/denominator`, and a symbol as its
+/// bare name (no quoting). Non-literal nodes carry no value of their own
+/// and render as nothing. This is on Node rather than NodeValue because a
+/// NodeValue holds every field at once, with no tag saying which one is
+/// meant; only the node's type tells an integer from a rational's
+/// numerator. There are no booleans or strings yet to render.
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.type_ {
+            NodeType::NODE_TYPE_NONE => write!(f, "nil"),
+            NodeType::NODE_TYPE_INTEGER => write!(f, "{}", self.value.integer),
+            NodeType::NODE_TYPE_RATIONAL => {
+                write!(f, "{}/{}", self.value.integer, self.value.denominator)
+            }
+            NodeType::NODE_TYPE_SYMBOL => {
+                write!(f, "{}", self.value.symbol.as_deref().unwrap_or(""))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Bases that integer values may be printed in.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum IntegerBase {
+    INTEGER_BASE_DECIMAL,
+    INTEGER_BASE_HEX,
+    INTEGER_BASE_BINARY,
+    INTEGER_BASE_OCTAL,
+}
+
+/// Render the value of literal NODE like its Display implementation, but
+/// with an integer written in BASE, prefixed with 0x, 0b or 0o. A negative
+/// integer is a `-` followed by its magnitude in BASE.
+fn node_value_string(node: &Node, base: IntegerBase) -> String {
+    if !integerp(node) {
+        return node.to_string();
+    }
+    let sign = if node.value.integer < 0 { "-" } else { "" };
+    let magnitude = node.value.integer.unsigned_abs();
+    match base {
+        IntegerBase::INTEGER_BASE_DECIMAL => node.to_string(),
+        IntegerBase::INTEGER_BASE_HEX => format!("{}0x{:x}", sign, magnitude),
+        IntegerBase::INTEGER_BASE_BINARY => format!("{}0b{:b}", sign, magnitude),
+        IntegerBase::INTEGER_BASE_OCTAL => format!("{}0o{:o}", sign, magnitude),
+    }
 }
 
-fn print_node(node: Option<&Node>, indent_level: usize) {
+fn print_node(
+    out: &mut dyn Write,
+    node: Option<&Node>,
+    indent_level: usize,
+    base: IntegerBase,
+) -> io::Result<()> {
     if node.is_none() {
-        return;
+        return Ok(());
     }
     let node = node.unwrap();
 
     // Print indent.
     for _ in 0..indent_level {
-        print!(" ");
+        write!(out, " ")?;
     }
     // Print type + value.
-    debug_assert_eq!(NodeType::NODE_TYPE_MAX as i32, 7, "print_node() must handle all node types");
+    debug_assert_eq!(NodeType::NODE_TYPE_MAX as i32, 9, "print_node() must handle all node types");
     match node.type_ {
-        NodeType::NODE_TYPE_NONE => print!("NONE"),
-        NodeType::NODE_TYPE_INTEGER => print!("INT:{}", node.value.integer),
+        NodeType::NODE_TYPE_NONE => write!(out, "NONE")?,
+        NodeType::NODE_TYPE_INTEGER => write!(out, "INT:{}", node_value_string(node, base))?,
+        NodeType::NODE_TYPE_RATIONAL => write!(out, "RAT:{}", node)?,
         NodeType::NODE_TYPE_SYMBOL => {
-            print!("SYM");
-            if let Some(symbol) = &node.value.symbol {
-                print!(":{}", symbol);
+            write!(out, "SYM")?;
+            if node.value.symbol.is_some() {
+                write!(out, ":{}", node)?;
             }
         }
-        NodeType::NODE_TYPE_BINARY_OPERATOR => print!("BINARY OPERATOR"),
-        NodeType::NODE_TYPE_VARIABLE_DECLARATION => print!("VARIABLE DECLARATION"),
+        NodeType::NODE_TYPE_BINARY_OPERATOR => write!(out, "BINARY OPERATOR")?,
+        NodeType::NODE_TYPE_VARIABLE_DECLARATION => write!(out, "VARIABLE DECLARATION")?,
         NodeType::NODE_TYPE_VARIABLE_DECLARATION_INITIALIZED => {
-            print!("VARIABLE DECLARATION INITIALIZED");
+            write!(out, "VARIABLE DECLARATION INITIALIZED")?;
         }
-        NodeType::NODE_TYPE_PROGRAM => print!("PROGRAM"),
-        _ => print!("UNKNOWN"),
+        NodeType::NODE_TYPE_PROGRAM => write!(out, "PROGRAM")?,
+        NodeType::NODE_TYPE_ERROR => write!(out, "ERROR")?,
+        _ => write!(out, "UNKNOWN")?,
     }
-    println!();
+    writeln!(out)?;
     // Print children.
     let mut child = node.children.as_deref();
     while let Some(child_node) = child {
-        print_node(Some(child_node), indent_level + 4);
+        print_node(out, Some(child_node), indent_level + 4, base)?;
+        child = child_node.next_child.as_deref();
+    }
+    Ok(())
+}
+
+/// Name of a node type as it appears in machine-readable AST output.
+fn node_type_name(type_: NodeType) -> &'static str {
+    match type_ {
+        NodeType::NODE_TYPE_NONE => "NONE",
+        NodeType::NODE_TYPE_INTEGER => "INTEGER",
+        NodeType::NODE_TYPE_RATIONAL => "RATIONAL",
+        NodeType::NODE_TYPE_SYMBOL => "SYMBOL",
+        NodeType::NODE_TYPE_VARIABLE_DECLARATION => "VARIABLE_DECLARATION",
+        NodeType::NODE_TYPE_VARIABLE_DECLARATION_INITIALIZED => "VARIABLE_DECLARATION_INITIALIZED",
+        NodeType::NODE_TYPE_BINARY_OPERATOR => "BINARY_OPERATOR",
+        NodeType::NODE_TYPE_PROGRAM => "PROGRAM",
+        NodeType::NODE_TYPE_ERROR => "ERROR",
+        NodeType::NODE_TYPE_MAX => "UNKNOWN",
+    }
+}
+
+/// STRING with double quotes and backslashes escaped, and control
+/// characters written as \uXXXX, as JSON accepts.
+fn escaped(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Print NODE and its children to OUT as a single-line JSON object.
+fn print_node_json(out: &mut dyn Write, node: &Node) -> io::Result<()> {
+    write!(out, "{{\"type\":\"{}\"", node_type_name(node.type_))?;
+    match node.type_ {
+        NodeType::NODE_TYPE_INTEGER => write!(out, ",\"value\":{}", node)?,
+        NodeType::NODE_TYPE_RATIONAL => write!(
+            out,
+            ",\"numerator\":{},\"denominator\":{}",
+            node.value.integer, node.value.denominator
+        )?,
+        NodeType::NODE_TYPE_SYMBOL => {
+            write!(out, ",\"value\":\"{}\"", escaped(&node.to_string()))?;
+        }
+        _ => {}
+    }
+    if node.children.is_some() {
+        write!(out, ",\"children\":[")?;
+        let mut child = node.children.as_deref();
+        while let Some(child_node) = child {
+            print_node_json(out, child_node)?;
+            child = child_node.next_child.as_deref();
+            if child.is_some() {
+                write!(out, ",")?;
+            }
+        }
+        write!(out, "]")?;
+    }
+    write!(out, "}}")
+}
+
+/// STRING escaped for a quoted DOT label. DOT has no \uXXXX escape, so
+/// besides quotes, backslashes and newlines, control characters are left
+/// out rather than written as escapes Graphviz would show verbatim.
+fn dot_escaped(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len());
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c.is_control() => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Print the DOT statements for NODE and its children to OUT, numbering
+/// nodes from ID. @return The next unused ID.
+fn print_node_dot_statements(
+    out: &mut dyn Write,
+    node: &Node,
+    id: usize,
+    base: IntegerBase,
+) -> io::Result<usize> {
+    write!(out, "    n{} [label=\"{}", id, node_type_name(node.type_))?;
+    if integerp(node) || rationalp(node) || symbolp(node) {
+        write!(out, " {}", dot_escaped(&node_value_string(node, base)))?;
+    }
+    writeln!(out, "\"];")?;
+    let mut next_id = id + 1;
+    let mut child = node.children.as_deref();
+    while let Some(child_node) = child {
+        writeln!(out, "    n{} -> n{};", id, next_id)?;
+        next_id = print_node_dot_statements(out, child_node, next_id, base)?;
+        child = child_node.next_child.as_deref();
+    }
+    Ok(next_id)
+}
+
+/// Print NODE and its children to OUT as a Graphviz DOT digraph.
+fn print_node_dot(out: &mut dyn Write, node: &Node, base: IntegerBase) -> io::Result<()> {
+    writeln!(out, "digraph AST {{")?;
+    print_node_dot_statements(out, node, 0, base)?;
+    writeln!(out, "}}")
+}
+
+/// Print NODE and its children to OUT one per line as `PATH TYPE [VALUE]`,
+/// where PATH is the dot-separated index of each node within its parent,
+/// starting at PATH for NODE. Editing one node changes only its own line,
+/// which keeps diffs of this output small.
+fn print_node_canonical(
+    out: &mut dyn Write,
+    node: &Node,
+    path: &str,
+    base: IntegerBase,
+) -> io::Result<()> {
+    write!(out, "{} {}", path, node_type_name(node.type_))?;
+    if integerp(node) || rationalp(node) {
+        write!(out, " {}", node_value_string(node, base))?;
+    } else if symbolp(node) {
+        write!(out, " \"{}\"", escaped(&node.to_string()))?;
+    }
+    writeln!(out)?;
+    let mut index = 0usize;
+    let mut child = node.children.as_deref();
+    while let Some(child_node) = child {
+        print_node_canonical(out, child_node, &format!("{}.{}", path, index), base)?;
+        index += 1;
+        child = child_node.next_child.as_deref();
+    }
+    Ok(())
+}
+
+/// Measurements of a compile, gathered for --stats-json.
+#[derive(Default)]
+struct Stats {
+    read: Duration,
+    lex: Duration,
+    parse: Duration,
+    tokens: usize,
+    bytes: u64,
+    /// Number of nodes of each type, by node_type_name().
+    node_types: BTreeMap<&'static str, usize>,
+}
+
+/// Count NODE and its children into STATS.
+fn stats_count_nodes(stats: &mut Stats, node: &Node) {
+    let name = node_type_name(node.type_);
+    *stats.node_types.entry(name).or_insert(0) += 1;
+    let mut child = node.children.as_deref();
+    while let Some(child_node) = child {
+        stats_count_nodes(stats, child_node);
         child = child_node.next_child.as_deref();
     }
 }
 
+/// Print STATS to OUT as a single-line JSON object.
+fn print_stats_json(out: &mut dyn Write, stats: &Stats) -> io::Result<()> {
+    write!(
+        out,
+        "{{\"phases_ns\":{{\"read\":{},\"lex\":{},\"parse\":{}}}",
+        stats.read.as_nanos(),
+        stats.lex.as_nanos(),
+        stats.parse.as_nanos()
+    )?;
+    let nodes: usize = stats.node_types.values().sum();
+    write!(out, ",\"nodes\":{}", nodes)?;
+    write!(out, ",\"node_types\":{{")?;
+    for (index, (name, count)) in stats.node_types.iter().enumerate() {
+        if index > 0 {
+            write!(out, ",")?;
+        }
+        write!(out, "\"{}\":{}", name, count)?;
+    }
+    write!(out, "}},\"tokens\":{}", stats.tokens)?;
+    writeln!(out, ",\"bytes\":{}}}", stats.bytes)
+}
+
+/// Renderers available to --dump-ast.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AstFormat {
+    AST_FORMAT_TREE,
+    AST_FORMAT_JSON,
+    AST_FORMAT_DOT,
+    AST_FORMAT_CANONICAL,
+}
+
 fn node_free(root: Option<Box<Node>>) {
     if root.is_none() {
         return;
@@ -477,6 +1328,42 @@ fn environment_get_by_symbol(env: &Environment, symbol: &str, result: &mut Node)
     status
 }
 
+/// Print every binding in ENV and its parents to OUT, one per line, as
+/// "[DEPTH] ID: TYPE", where DEPTH counts scopes outward from ENV. A
+/// binding's node only stands for its type; declarations have no value
+/// yet, so none is printed.
+/// Bindings are listed in storage order (most recently created first,
+/// innermost scope first) unless SORTED is set, in which case they are
+/// sorted by ID; the sort is stable, so a shadowed ID lists its inner
+/// binding first.
+fn print_environment(out: &mut dyn Write, env: &Environment, sorted: bool) -> io::Result<()> {
+    let mut bindings = Vec::new();
+    let mut depth = 0usize;
+    let mut scope = Some(env);
+    while let Some(current) = scope {
+        let mut binding_it = current.bind.as_deref();
+        while let Some(binding) = binding_it {
+            bindings.push((depth, binding));
+            binding_it = binding.next.as_deref();
+        }
+        depth += 1;
+        scope = current.parent.as_deref();
+    }
+    if sorted {
+        bindings.sort_by_key(|(_, binding)| binding.id.to_string());
+    }
+    for (depth, binding) in bindings {
+        writeln!(
+            out,
+            "[{}] {}: {}",
+            depth,
+            binding.id,
+            node_type_name(binding.value.type_)
+        )?;
+    }
+    Ok(())
+}
+
 // @return Boolean-like value; 1 for success, 0 for failure.
 fn token_string_equalp(string: &str, token: &Token, source: &[u8]) -> i32 {
     if string.is_empty() {
@@ -498,24 +1385,82 @@ fn token_string_equalp(string: &str, token: &Token, source: &[u8]) -> i32 {
     1
 }
 
-/// @return Boolean-like value; 1 upon success, 0 for failure.
-fn parse_integer(source: &[u8], token: &Token, node: &mut Node) -> i32 {
-    if token.end <= token.beginning || token.end > source.len() {
-        return 0;
+/// What to do with an integer that does not fit in integer_t.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OverflowPolicy {
+    /// Refuse the value; the caller reports an error.
+    OVERFLOW_POLICY_CHECKED,
+    /// Wrap around in two's complement.
+    OVERFLOW_POLICY_WRAPPING,
+    /// Clamp to the smallest or largest representable value.
+    OVERFLOW_POLICY_SATURATING,
+}
+
+/**
+ * @retval 0 Failure; token is not an integer.
+ * @retval 1 Success.
+ * @retval 2 Token is an integer that overflows integer_t under POLICY.
+ */
+fn parse_integer(source: &[u8], token: &Token, node: &mut Node, policy: OverflowPolicy) -> i32 {
+    if token.end <= token.beginning || token.end > source.len() {
+        return 0;
     }
     let token_slice = &source[token.beginning..token.end];
     if token_slice.len() == 1 && token_slice[0] == b'0' {
         node.type_ = NodeType::NODE_TYPE_INTEGER;
         node.value.integer = 0;
     } else if let Ok(token_str) = std::str::from_utf8(token_slice) {
-        if let Ok(value) = token_str.parse::<i64>() {
-            if value == 0 {
-                return 0;
+        match token_str.parse::<integer_t>() {
+            Ok(value) => {
+                if value == 0 {
+                    return 0;
+                }
+                node.type_ = NodeType::NODE_TYPE_INTEGER;
+                node.value.integer = value;
+            }
+            Err(e) => {
+                // parse() reports overflow as soon as the value outgrows
+                // integer_t, before it has seen the rest of the token, so
+                // only a sign and digits make an overflowing integer.
+                let digits = match token_slice.first() {
+                    Some(b'-' | b'+') => &token_slice[1..],
+                    _ => token_slice,
+                };
+                if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+                    return 0;
+                }
+                let negative = match e.kind() {
+                    IntErrorKind::PosOverflow => false,
+                    IntErrorKind::NegOverflow => true,
+                    _ => return 0,
+                };
+                let value = match policy {
+                    OverflowPolicy::OVERFLOW_POLICY_CHECKED => return 2,
+                    OverflowPolicy::OVERFLOW_POLICY_WRAPPING => {
+                        let mut value: integer_t = 0;
+                        for digit in digits {
+                            let digit = (digit - b'0') as integer_t;
+                            value = value.wrapping_mul(10);
+                            value = if negative {
+                                value.wrapping_sub(digit)
+                            } else {
+                                value.wrapping_add(digit)
+                            };
+                        }
+                        value
+                    }
+                    OverflowPolicy::OVERFLOW_POLICY_SATURATING => {
+                        if negative {
+                            integer_t::MIN
+                        } else {
+                            integer_t::MAX
+                        }
+                    }
+                };
+                node.type_ = NodeType::NODE_TYPE_INTEGER;
+                node.value.integer = value;
             }
-            node.type_ = NodeType::NODE_TYPE_INTEGER;
-            node.value.integer = value;
-        } else {
-            return 0;
         }
     } else {
         return 0;
@@ -523,18 +1468,84 @@ fn parse_integer(source: &[u8], token: &Token, node: &mut Node) -> i32 {
     1
 }
 
+/// Parse TOKEN as a rational like `3/4`: two integers joined by a single
+/// slash, with no whitespace in between.
+/// @retval 0 TOKEN is not a rational.
+/// @retval 1 NODE now holds the rational in lowest terms.
+/// @retval 2 A part overflowed under OVERFLOW_POLICY_CHECKED, or the
+///           reduced rational does not fit.
+/// @retval 3 The denominator is zero.
+fn parse_rational(source: &[u8], token: &Token, node: &mut Node, policy: OverflowPolicy) -> i32 {
+    if token.end <= token.beginning || token.end > source.len() {
+        return 0;
+    }
+    let Some(slash) = source[token.beginning..token.end]
+        .iter()
+        .position(|&c| c == b'/')
+    else {
+        return 0;
+    };
+    let numerator_token = Token {
+        beginning: token.beginning,
+        end: token.beginning + slash,
+    };
+    let denominator_token = Token {
+        beginning: numerator_token.end + 1,
+        end: token.end,
+    };
+    let mut numerator = node_allocate();
+    let mut denominator = node_allocate();
+    let numerator_status = parse_integer(source, &numerator_token, &mut numerator, policy);
+    let denominator_status = parse_integer(source, &denominator_token, &mut denominator, policy);
+    if numerator_status == 0 || denominator_status == 0 {
+        return 0;
+    }
+    if numerator_status == 2 || denominator_status == 2 {
+        return 2;
+    }
+    if denominator.value.integer == 0 {
+        return 3;
+    }
+    match node_rational(numerator.value.integer, denominator.value.integer) {
+        Some(rational) => *node = *rational,
+        None => return 2,
+    }
+    1
+}
+
 type ParsingContext = ParsingContextStruct;
 
 struct ParsingContextStruct {
     // FIXME: "struct ParsingContext *parent;" ???
     types: Box<Environment>,
     variables: Box<Environment>,
+    overflow_policy: OverflowPolicy,
+    /// Reject loose syntax that is otherwise tolerated, such as a bare
+    /// integer at the top level of a program.
+    strict: bool,
+    /// Make declaring an already declared variable an error instead of
+    /// letting the last declaration win.
+    forbid_redefinition: bool,
+    /// Columns a tab advances to the next multiple of in reported
+    /// positions. Must not be zero.
+    tab_width: usize,
+    /// Longest token, in bytes, that is accepted; anything longer is an
+    /// error before it is looked at any further.
+    max_token_length: usize,
+    /// Most errors parse_all() keeps; any past it are only counted.
+    max_errors: usize,
 }
 
 fn parse_context_create() -> Box<ParsingContextStruct> {
     let mut ctx = Box::new(ParsingContextStruct {
         types: environment_create(None),
         variables: environment_create(None),
+        overflow_policy: OverflowPolicy::OVERFLOW_POLICY_CHECKED,
+        strict: false,
+        forbid_redefinition: false,
+        tab_width: 1,
+        max_token_length: 1 << 20,
+        max_errors: 20,
     });
     if environment_set(&mut ctx.types, node_symbol("integer"), node_integer(0)) == 0 {
         println!("ERROR: Failed to set builtin type in types environment.");
@@ -542,167 +1553,1677 @@ fn parse_context_create() -> Box<ParsingContextStruct> {
     ctx
 }
 
-fn parse_expr(
+/// Parse exactly one expression from SOURCE, starting at END, into RESULT.
+/// END is left pointing just past the parsed expression, so repeated calls
+/// walk the source one expression at a time. RESULT is left untouched when
+/// no expression remains.
+fn parse_one(
     context: &mut ParsingContext,
     source: &[u8],
     end: &mut usize,
     result: &mut Node,
 ) -> Error {
-    let _token_count: usize = 0;
     let mut current_token = Token {
-        beginning: 0,
-        end: 0,
+        beginning: *end,
+        end: *end,
     };
-    let mut err;
 
-    loop {
-        err = lex(source, current_token.end, &mut current_token);
+    let mut err = lex(context, source, current_token.end, &mut current_token);
+    if err.type_ != ErrorType::ERROR_NONE {
+        return err;
+    }
+    *end = current_token.end;
+    let token_length = current_token.end.saturating_sub(current_token.beginning);
+    if token_length == 0 {
+        return err;
+    }
+
+    // An expression can not begin with a closing delimiter or separator.
+    let first = source[current_token.beginning];
+    if token_length == 1 && b"),:".contains(&first) {
+        error_prep_at(
+            &mut err,
+            ErrorType::ERROR_SYNTAX,
+            &format!("Unexpected '{}'", first as char),
+            span_create(source, &current_token, context.tab_width),
+        );
+        return err;
+    }
+
+    let status = parse_integer(source, &current_token, result, context.overflow_policy);
+    if status == 2 {
+        error_prep_at(
+            &mut err,
+            ErrorType::ERROR_SYNTAX,
+            "Integer literal too large for integer type",
+            span_create(source, &current_token, context.tab_width),
+        );
+        return err;
+    }
+    if status != 0 {
+        // look ahead for binary ops that include integers.
+        let _lhs_integer = (*result).clone();
+
+        // TODO: Check for valid integer operator.
+        // It would be cool to use an operator environment to look up
+        // operators instead of hard-coding them. This would eventually
+        // allow for user-defined operators, or stuff like that!
+        // Until then there is nothing to look ahead for, so the next token
+        // is left alone, errors and all, for the next expression.
+        *end = current_token.end;
+
+        return err;
+    }
+
+    let status = parse_rational(source, &current_token, result, context.overflow_policy);
+    if status == 2 || status == 3 {
+        error_prep_at(
+            &mut err,
+            ErrorType::ERROR_SYNTAX,
+            if status == 2 {
+                "Rational literal too large for integer type"
+            } else {
+                "Rational literal has a zero denominator"
+            },
+            span_create(source, &current_token, context.tab_width),
+        );
+        return err;
+    }
+    if status != 0 {
+        *end = current_token.end;
+        return err;
+    }
+
+    // TODO: Check for unary prefix operators.
+
+    // TODO: Check that it isn't a binary operator (we should encounter left
+    // side first and peek forward, rather than encounter it at top level).
+
+    let mut text = "";
+    err = token_text(source, &current_token, &mut text);
+    if err.type_ != ErrorType::ERROR_NONE {
+        err.span = Some(span_create(source, &current_token, context.tab_width));
+        return err;
+    }
+    let symbol = node_symbol(symbol_name(text));
+    let symbol_token = current_token;
+
+    //*result = *symbol;
+
+    // TODO: Check if valid symbol for variable environment, then
+    // attempt to pattern match variable access, assignment,
+    // declaration, or declaration with initialization.
+
+    err = lex(context, source, current_token.end, &mut current_token);
+    if err.type_ != ErrorType::ERROR_NONE {
+        return err;
+    }
+    *end = current_token.end;
+    let token_length = current_token.end.saturating_sub(current_token.beginning);
+    // Nothing follows, so the symbol stands on its own.
+    if token_length == 0 {
+        *result = *symbol;
+        return err;
+    }
+
+    if token_string_equalp(":", &current_token, source) != 0 {
+        let colon_token = current_token;
+        err = lex(context, source, current_token.end, &mut current_token);
         if err.type_ != ErrorType::ERROR_NONE {
-            break;
+            return err;
         }
         *end = current_token.end;
         let token_length = current_token.end.saturating_sub(current_token.beginning);
-        if token_length == 0 {
-            break;
+        // A type annotation needs a type name; a lone delimiter is not one.
+        if token_length == 0
+            || (token_length == 1 && DELIMITERS.contains(&source[current_token.beginning]))
+        {
+            error_prep_at(
+                &mut err,
+                ErrorType::ERROR_SYNTAX,
+                "Expected a type name after ':'",
+                span_create(source, &colon_token, context.tab_width),
+            );
+            return err;
         }
-        if parse_integer(source, &current_token, result) != 0 {
-            // look ahead for binary ops that include integers.
-            let _lhs_integer = (*result).clone();
-            err = lex(source, current_token.end, &mut current_token);
-            if err.type_ != ErrorType::ERROR_NONE {
+
+        err = token_text(source, &current_token, &mut text);
+        if err.type_ != ErrorType::ERROR_NONE {
+            err.span = Some(span_create(source, &current_token, context.tab_width));
+            return err;
+        }
+        let expected_type_symbol = node_symbol(symbol_name(text));
+        let status = environment_get(&context.types, &expected_type_symbol, result);
+        if status == 0 {
+            error_prep_at(
+                &mut err,
+                ErrorType::ERROR_TYPE,
+                "Invalid type within variable declaration",
+                span_create(source, &current_token, context.tab_width),
+            );
+            return err;
+        } else {
+            //printf("Found valid type: ");
+            //print_node(expected_type_symbol,0);
+            //putchar('\n');
+
+            let mut var_decl = node_allocate();
+            var_decl.type_ = NodeType::NODE_TYPE_VARIABLE_DECLARATION;
+
+            let mut type_node = node_allocate();
+            type_node.type_ = result.type_;
+
+            if context.forbid_redefinition
+                && environment_get(&context.variables, &symbol, result) != 0
+            {
+                error_prep_at(
+                    &mut err,
+                    ErrorType::ERROR_GENERIC,
+                    &format!("Redefinition of {}", symbol),
+                    span_create(source, &symbol_token, context.tab_width),
+                );
                 return err;
             }
-            *end = current_token.end;
+            environment_set(&mut context.variables, symbol.clone(), type_node.clone());
 
-            // TODO: Check for valid integer operator.
-            // It would be cool to use an operator environment to look up
-            // operators instead of hard-coding them. This would eventually
-            // allow for user-defined operators, or stuff like that!
+            node_add_child(&mut var_decl, type_node);
+            node_add_child(&mut var_decl, symbol);
 
-        } else {
-            // TODO: Check for unary prefix operators.
+            *result = (*var_decl).clone();
+
+            return ok();
+        }
+    }
 
-            // TODO: Check that it isn't a binary operator (we should encounter left
-            // side first and peek forward, rather than encounter it at top level).
+    error_prep_at(
+        &mut err,
+        ErrorType::ERROR_SYNTAX,
+        "Unrecognized token",
+        span_create(source, &current_token, context.tab_width),
+    );
+    err
+}
 
-            let symbol = node_symbol_from_buffer(
-                &source[current_token.beginning..current_token.beginning + token_length],
-            );
+/// In strict mode, reject EXPRESSION, parsed from SOURCE at START, if it
+/// is a bare integer. The error points at the integer's token.
+fn check_strict(context: &ParsingContext, source: &[u8], start: usize, expression: &Node) -> Error {
+    let mut err = ok();
+    if !context.strict || !integerp(expression) {
+        return err;
+    }
+    let mut token = Token {
+        beginning: start,
+        end: start,
+    };
+    err = lex(context, source, start, &mut token);
+    if err.type_ != ErrorType::ERROR_NONE {
+        return err;
+    }
+    error_prep_at(
+        &mut err,
+        ErrorType::ERROR_SYNTAX,
+        "Bare integer at top level is not allowed in strict mode",
+        span_create(source, &token, context.tab_width),
+    );
+    err
+}
 
-            //*result = *symbol;
+/// Parse every expression in SOURCE, starting at END, as children of a
+/// PROGRAM node in RESULT. On error, RESULT holds the expressions parsed
+/// so far. Source with no tokens, like an empty file or one holding only
+/// whitespace or a shebang, is not an error: RESULT is a PROGRAM node
+/// with no children.
+fn parse_expr(
+    context: &mut ParsingContext,
+    source: &[u8],
+    end: &mut usize,
+    result: &mut Node,
+) -> Error {
+    result.type_ = NodeType::NODE_TYPE_PROGRAM;
+    loop {
+        let start = *end;
+        let mut expression = node_allocate();
+        let mut err = parse_one(context, source, end, &mut expression);
+        if err.type_ != ErrorType::ERROR_NONE {
+            return err;
+        }
+        if nonep(&expression) {
+            break;
+        }
+        err = check_strict(context, source, start, &expression);
+        if err.type_ != ErrorType::ERROR_NONE {
+            return err;
+        }
+        node_add_child(result, expression);
+    }
+    ok()
+}
+
+/// Parse SOURCE as exactly one expression into RESULT. Anything after the
+/// expression is an error pointing at the first leftover token. Strict
+/// mode applies as in parse_expr().
+fn parse_single(context: &mut ParsingContext, source: &[u8], result: &mut Node) -> Error {
+    let mut end = 0usize;
+    let mut err = parse_one(context, source, &mut end, result);
+    if err.type_ != ErrorType::ERROR_NONE {
+        return err;
+    }
+    if nonep(result) {
+        error_prep(&mut err, ErrorType::ERROR_SYNTAX, "Expected an expression");
+        return err;
+    }
+    err = check_strict(context, source, 0, result);
+    if err.type_ != ErrorType::ERROR_NONE {
+        return err;
+    }
+    let mut leftover = Token {
+        beginning: end,
+        end,
+    };
+    err = lex(context, source, end, &mut leftover);
+    if err.type_ != ErrorType::ERROR_NONE {
+        return err;
+    }
+    if leftover.end != leftover.beginning {
+        error_prep_at(
+            &mut err,
+            ErrorType::ERROR_SYNTAX,
+            "Unexpected trailing tokens",
+            span_create(source, &leftover, context.tab_width),
+        );
+    }
+    err
+}
 
-            // TODO: Check if valid symbol for variable environment, then
-            // attempt to pattern match variable access, assignment,
-            // declaration, or declaration with initialization.
+/// Span of the error in ERRORS that begins earliest in the source, in
+/// whatever order ERRORS were collected; None if no error has a span.
+fn first_error_span(errors: &[Error]) -> Option<Span> {
+    errors
+        .iter()
+        .filter_map(|err| err.span)
+        .min_by_key(|span| span.beginning)
+}
 
-            err = lex(source, current_token.end, &mut current_token);
-            if err.type_ != ErrorType::ERROR_NONE {
-                return err;
-            }
-            *end = current_token.end;
-            let token_length = current_token.end.saturating_sub(current_token.beginning);
-            if token_length == 0 {
-                break;
-            }
+/// Sort ERRORS into source order by where their spans begin, keeping
+/// errors that begin at the same place in the order they were found.
+/// Errors without a span go last.
+fn sort_errors(errors: &mut [Error]) {
+    errors.sort_by_key(|err| err.span.map_or(usize::MAX, |span| span.beginning));
+}
 
-            if token_string_equalp(":", &current_token, source) != 0 {
-                err = lex(source, current_token.end, &mut current_token);
-                if err.type_ != ErrorType::ERROR_NONE {
-                    return err;
+/// Parse every expression in SOURCE into the PROGRAM node RESULT like
+/// parse_expr(), but recover from errors instead of stopping at the first.
+/// Each error is appended to ERRORS and leaves an ERROR node in RESULT in
+/// place of the failed expression; parsing resumes on the next line, so any
+/// expressions after the failing token on that same line are skipped too.
+/// Only the first CONTEXT.max_errors errors are appended; parsing goes on
+/// past them so that the rest can be counted.
+/// @return The number of errors found but not appended.
+fn parse_all(
+    context: &mut ParsingContext,
+    source: &[u8],
+    result: &mut Node,
+    errors: &mut Vec<Error>,
+) -> usize {
+    let mut omitted = 0usize;
+    let mut end = 0usize;
+    loop {
+        let start = end;
+        let err = parse_expr(context, source, &mut end, result);
+        if err.type_ == ErrorType::ERROR_NONE {
+            return omitted;
+        }
+        // Skip the rest of the line the error was found on, always making
+        // progress so the same failure can not be hit forever.
+        end = end.max(start + 1).min(source.len());
+        while end < source.len() && source[end] != b'\n' {
+            end += 1;
+        }
+        if errors.len() < context.max_errors {
+            errors.push(err);
+        } else {
+            omitted += 1;
+        }
+        let mut hole = node_allocate();
+        hole.type_ = NodeType::NODE_TYPE_ERROR;
+        node_add_child(result, hole);
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut overflow_policy = OverflowPolicy::OVERFLOW_POLICY_CHECKED;
+    let mut strict = false;
+    let mut forbid_redefinition = false;
+    let mut encoding = Encoding::ENCODING_UTF8;
+    let mut count_only = false;
+    let mut trace_lex = false;
+    let mut verify_lex = false;
+    let mut stats_json = false;
+    let mut output_path: Option<&str> = None;
+    let mut error_format = ErrorFormat::ERROR_FORMAT_HUMAN;
+    let mut dump_env = false;
+    let mut sort_env = false;
+    let mut tab_width = 1usize;
+    let mut max_token_length: Option<usize> = None;
+    let mut max_errors: Option<usize> = None;
+    let mut ast_format = AstFormat::AST_FORMAT_TREE;
+    let mut int_base = IntegerBase::INTEGER_BASE_DECIMAL;
+    let mut check_only = false;
+    let mut single = false;
+    let mut ast_depth_limit: Option<usize> = None;
+    let mut paths = Vec::new();
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        if arg == "--overflow" {
+            i += 1;
+            overflow_policy = match args.get(i).map(String::as_str) {
+                Some("checked") => OverflowPolicy::OVERFLOW_POLICY_CHECKED,
+                Some("wrapping") => OverflowPolicy::OVERFLOW_POLICY_WRAPPING,
+                Some("saturating") => OverflowPolicy::OVERFLOW_POLICY_SATURATING,
+                _ => {
+                    let mut err = ok();
+                    error_prep(
+                        &mut err,
+                        ErrorType::ERROR_ARGUMENTS,
+                        "--overflow expects one of: checked, wrapping, saturating",
+                    );
+                    print_error(&err);
+                    return;
                 }
-                *end = current_token.end;
-                let token_length = current_token.end.saturating_sub(current_token.beginning);
-                if token_length == 0 {
-                    break;
+            };
+        } else if arg == "--encoding" {
+            i += 1;
+            encoding = match args.get(i).map(String::as_str) {
+                Some("utf8") => Encoding::ENCODING_UTF8,
+                Some("latin1") => Encoding::ENCODING_LATIN1,
+                _ => {
+                    let mut err = ok();
+                    error_prep(
+                        &mut err,
+                        ErrorType::ERROR_ARGUMENTS,
+                        "--encoding expects one of: utf8, latin1",
+                    );
+                    print_error(&err);
+                    return;
                 }
-
-                let expected_type_symbol = node_symbol_from_buffer(
-                    &source[current_token.beginning..current_token.beginning + token_length],
-                );
-                let status = environment_get(&context.types, &expected_type_symbol, result);
-                if status == 0 {
+            };
+        } else if arg == "--count-tokens" {
+            count_only = true;
+        } else if arg == "--error-format" {
+            i += 1;
+            error_format = match args.get(i).map(String::as_str) {
+                Some("human") => ErrorFormat::ERROR_FORMAT_HUMAN,
+                Some("json") => ErrorFormat::ERROR_FORMAT_JSON,
+                _ => {
+                    let mut err = ok();
                     error_prep(
                         &mut err,
-                        ErrorType::ERROR_TYPE,
-                        "Invalid type within variable declaration",
+                        ErrorType::ERROR_ARGUMENTS,
+                        "--error-format expects one of: human, json",
                     );
-                    println!(
-                        "\nINVALID TYPE: \"{}\"",
-                        expected_type_symbol.value.symbol.as_deref().unwrap_or("")
+                    print_error(&err);
+                    return;
+                }
+            };
+        } else if arg == "--trace-lex" {
+            trace_lex = true;
+        } else if arg == "-o" {
+            i += 1;
+            output_path = match args.get(i) {
+                Some(path) => Some(path.as_str()),
+                None => {
+                    let mut err = ok();
+                    error_prep(
+                        &mut err,
+                        ErrorType::ERROR_ARGUMENTS,
+                        "-o expects a file path",
+                    );
+                    print_error(&err);
+                    return;
+                }
+            };
+        } else if arg == "--stats-json" {
+            stats_json = true;
+        } else if arg == "--verify-lex" {
+            verify_lex = true;
+        } else if arg == "--int-base" {
+            i += 1;
+            int_base = match args.get(i).map(String::as_str) {
+                Some("dec") => IntegerBase::INTEGER_BASE_DECIMAL,
+                Some("hex") => IntegerBase::INTEGER_BASE_HEX,
+                Some("bin") => IntegerBase::INTEGER_BASE_BINARY,
+                Some("oct") => IntegerBase::INTEGER_BASE_OCTAL,
+                _ => {
+                    let mut err = ok();
+                    error_prep(
+                        &mut err,
+                        ErrorType::ERROR_ARGUMENTS,
+                        "--int-base expects one of: dec, hex, bin, oct",
                     );
-                    return err;
-                } else {
-                    //printf("Found valid type: ");
-                    //print_node(expected_type_symbol,0);
-                    //putchar('\n');
+                    print_error(&err);
+                    return;
+                }
+            };
+        } else if arg == "--dump-env" {
+            dump_env = true;
+        } else if arg == "--sort-env" {
+            sort_env = true;
+        } else if arg == "--tab-width" {
+            i += 1;
+            tab_width = match args.get(i).and_then(|width| width.parse::<usize>().ok()) {
+                Some(width) if width > 0 => width,
+                _ => {
+                    let mut err = ok();
+                    error_prep(
+                        &mut err,
+                        ErrorType::ERROR_ARGUMENTS,
+                        "--tab-width expects a positive integer",
+                    );
+                    print_error(&err);
+                    return;
+                }
+            };
+        } else if arg == "--max-token-length" {
+            i += 1;
+            max_token_length = match args.get(i).and_then(|length| length.parse::<usize>().ok()) {
+                Some(length) if length > 0 => Some(length),
+                _ => {
+                    let mut err = ok();
+                    error_prep(
+                        &mut err,
+                        ErrorType::ERROR_ARGUMENTS,
+                        "--max-token-length expects a positive integer",
+                    );
+                    print_error(&err);
+                    return;
+                }
+            };
+        } else if arg == "--ast-depth-limit" {
+            i += 1;
+            ast_depth_limit = match args.get(i).and_then(|limit| limit.parse::<usize>().ok()) {
+                Some(limit) if limit > 0 => Some(limit),
+                _ => {
+                    let mut err = ok();
+                    error_prep(
+                        &mut err,
+                        ErrorType::ERROR_ARGUMENTS,
+                        "--ast-depth-limit expects a positive integer",
+                    );
+                    print_error(&err);
+                    return;
+                }
+            };
+        } else if arg == "--max-errors" {
+            i += 1;
+            max_errors = match args.get(i).and_then(|count| count.parse::<usize>().ok()) {
+                Some(count) if count > 0 => Some(count),
+                _ => {
+                    let mut err = ok();
+                    error_prep(
+                        &mut err,
+                        ErrorType::ERROR_ARGUMENTS,
+                        "--max-errors expects a positive integer",
+                    );
+                    print_error(&err);
+                    return;
+                }
+            };
+        } else if arg == "--strict" {
+            strict = true;
+        } else if arg == "--forbid-redefinition" {
+            forbid_redefinition = true;
+        } else if arg == "--dump-ast" || arg.starts_with("--dump-ast=") {
+            ast_format = match arg.strip_prefix("--dump-ast=") {
+                None | Some("tree") => AstFormat::AST_FORMAT_TREE,
+                Some("json") => AstFormat::AST_FORMAT_JSON,
+                Some("dot") => AstFormat::AST_FORMAT_DOT,
+                Some("canonical") => AstFormat::AST_FORMAT_CANONICAL,
+                _ => {
+                    let mut err = ok();
+                    error_prep(
+                        &mut err,
+                        ErrorType::ERROR_ARGUMENTS,
+                        "--dump-ast expects one of: tree, json, dot, canonical",
+                    );
+                    print_error(&err);
+                    return;
+                }
+            };
+        } else if arg == "--single" {
+            single = true;
+        } else if arg == "--check" {
+            check_only = true;
+        } else {
+            paths.push(arg);
+        }
+        i += 1;
+    }
 
-                    let mut var_decl = node_allocate();
-                    var_decl.type_ = NodeType::NODE_TYPE_VARIABLE_DECLARATION;
+    if paths.is_empty() {
+        print_usage(&args[0]);
+        return;
+    }
+    // Files share one context so later files see earlier declarations.
+    let mut context = parse_context_create();
+    context.overflow_policy = overflow_policy;
+    context.strict = strict;
+    context.forbid_redefinition = forbid_redefinition;
+    context.tab_width = tab_width;
+    if let Some(length) = max_token_length {
+        context.max_token_length = length;
+    }
+    if let Some(count) = max_errors {
+        context.max_errors = count;
+    }
+    let mut programs = Vec::new();
+    let mut stats = Stats::default();
+    let mut failed = false;
+    let mut unreadable = false;
+    let mut counts = Vec::new();
+    for path in paths {
+        let read_start = Instant::now();
+        #[cfg(all(unix, feature = "mmap"))]
+        let mapping = file_map(path);
+        #[cfg(all(unix, feature = "mmap"))]
+        let contents = match &mapping {
+            Some(mapping) => Some(Cow::Borrowed(mapping.bytes())),
+            None => file_contents(path).map(Cow::Owned),
+        };
+        #[cfg(not(all(unix, feature = "mmap")))]
+        let contents: Option<Cow<[u8]>> = file_contents(path).map(Cow::Owned);
+        // A read buffer ends in a NUL that is not part of the file; a
+        // mapping is exactly the file.
+        let bytes_read = match &contents {
+            Some(Cow::Owned(contents)) => contents.len() - 1,
+            Some(Cow::Borrowed(contents)) => contents.len(),
+            None => 0,
+        };
+        // Valid UTF-8 is left alone, so ASCII-only Latin-1 files cost nothing.
+        let contents = contents.map(|contents| {
+            if encoding == Encoding::ENCODING_LATIN1 && std::str::from_utf8(&contents).is_err() {
+                latin1_to_utf8(&contents).into()
+            } else {
+                contents
+            }
+        });
+        stats.read += read_start.elapsed();
+        stats.bytes += bytes_read as u64;
 
-                    let mut type_node = node_allocate();
-                    type_node.type_ = result.type_;
+        if let Some(contents) = contents {
+            //printf("Contents of %s:\n---\n\"%s\"\n---\n", path, contents);
 
-                    node_add_child(&mut var_decl, type_node);
-                    node_add_child(&mut var_decl, symbol);
+            if trace_lex {
+                check_written(trace_tokens(&mut io::stderr(), &context, &contents));
+            }
 
-                    *result = (*var_decl).clone();
+            // Lexing is otherwise interleaved with parsing, so it is only
+            // timed, in a pass of its own, when the stats are wanted.
+            if stats_json {
+                let lex_start = Instant::now();
+                let mut count = 0usize;
+                count_tokens(&context, &contents, &mut count);
+                stats.lex += lex_start.elapsed();
+                stats.tokens += count;
+            }
 
-                    return ok();
+            // A lexical error is left for the parser to report.
+            if verify_lex {
+                let mut tokens = Vec::new();
+                if lex_tokens(&context, &contents, &mut tokens).type_ == ErrorType::ERROR_NONE {
+                    let err = verify_token_coverage(&contents, &tokens);
+                    if err.type_ != ErrorType::ERROR_NONE {
+                        report_error(&err, error_format, Some(path));
+                        failed = true;
+                    }
                 }
             }
 
-            print!("Unrecognized token: ");
-            print_token(source, &current_token);
-            println!();
+            if count_only {
+                let mut count = 0usize;
+                let err = count_tokens(&context, &contents, &mut count);
+                // A partial count would pass for the whole file's.
+                if err.type_ != ErrorType::ERROR_NONE {
+                    report_error(&err, error_format, Some(path));
+                    failed = true;
+                    continue;
+                }
+                counts.push(count);
+                continue;
+            }
 
-            return err;
+            let mut program = node_allocate();
+
+            if check_only {
+                let mut errors = Vec::new();
+                let mut omitted = parse_all(&mut context, &contents, &mut program, &mut errors);
+                if let Some(limit) = ast_depth_limit {
+                    let err = check_ast_depth(&program, limit);
+                    if err.type_ != ErrorType::ERROR_NONE {
+                        if errors.len() < context.max_errors {
+                            errors.push(err);
+                        } else {
+                            omitted += 1;
+                        }
+                    }
+                }
+                sort_errors(&mut errors);
+                // Sorted errors with a span come first, earliest first.
+                debug_assert_eq!(
+                    errors.first().and_then(|err| err.span),
+                    first_error_span(&errors)
+                );
+                if !errors.is_empty() {
+                    if error_format == ErrorFormat::ERROR_FORMAT_HUMAN {
+                        println!("{}:", path);
+                    }
+                    for err in &errors {
+                        report_error(err, error_format, Some(path));
+                    }
+                    if omitted > 0 {
+                        let plural = if omitted == 1 { "" } else { "s" };
+                        let message = format!("... and {} more error{}", omitted, plural);
+                        if error_format == ErrorFormat::ERROR_FORMAT_HUMAN {
+                            println!("{}", message);
+                        } else {
+                            let mut err = ok();
+                            error_prep(&mut err, ErrorType::ERROR_GENERIC, &message);
+                            print_error_json(&err, Some(path));
+                        }
+                    }
+                    failed = true;
+                }
+                node_free(Some(program));
+                continue;
+            }
+
+            let parse_start = Instant::now();
+            let err = if single {
+                parse_single(&mut context, &contents, &mut program)
+            } else {
+                let mut contents_it = 0usize;
+                parse_expr(&mut context, &contents, &mut contents_it, &mut program)
+            };
+            stats.parse += parse_start.elapsed();
+
+            report_error(&err, error_format, Some(path));
+            if err.type_ != ErrorType::ERROR_NONE {
+                failed = true;
+            }
+            programs.push(*program);
+        } else {
+            failed = true;
+            unreadable = true;
         }
+    }
+
+    // Output is only opened once every input has been read, so an input
+    // that could not be read never leaves an empty or partial -o FILE.
+    if unreadable || (check_only && failed) {
+        std::process::exit(1);
+    }
+    if check_only {
+        return;
+    }
+    // Every renderer writes to OUT, so it is decided here, once.
+    let mut out: Box<dyn Write> = match output_path {
+        Some(output_path) => match File::create(output_path) {
+            Ok(file) => Box::new(BufWriter::new(file)),
+            Err(_) => {
+                println!("Could not create file at {}", output_path);
+                return;
+            }
+        },
+        None => Box::new(io::stdout()),
+    };
 
-        print!("Intermediate node: ");
-        print_node(Some(&*result), 0);
-        println!();
+    if count_only {
+        for count in counts {
+            check_written(writeln!(out, "{}", count));
+        }
+        check_written(out.flush());
+        if failed {
+            std::process::exit(1);
+        }
+        return;
     }
 
-    err
+    // A --single expression is not a program, so each is printed bare.
+    let roots = if single {
+        programs
+    } else {
+        let mut program = node_allocate();
+        let err = merge_programs(programs, &mut program);
+        report_error(&err, error_format, None);
+        if err.type_ != ErrorType::ERROR_NONE {
+            failed = true;
+        }
+        vec![*program]
+    };
+
+    for root in roots {
+        if let Some(limit) = ast_depth_limit {
+            let err = check_ast_depth(&root, limit);
+            if err.type_ != ErrorType::ERROR_NONE {
+                report_error(&err, error_format, None);
+                failed = true;
+                node_free(Some(Box::new(root)));
+                continue;
+            }
+        }
+        // With --stats-json the output is that one JSON object alone.
+        if stats_json {
+            stats_count_nodes(&mut stats, &root);
+        } else {
+            check_written(match ast_format {
+                AstFormat::AST_FORMAT_TREE => {
+                    print_node(&mut out, Some(&root), 0, int_base).and_then(|_| writeln!(out))
+                }
+                AstFormat::AST_FORMAT_JSON => {
+                    print_node_json(&mut out, &root).and_then(|_| writeln!(out))
+                }
+                AstFormat::AST_FORMAT_DOT => print_node_dot(&mut out, &root, int_base),
+                AstFormat::AST_FORMAT_CANONICAL => {
+                    print_node_canonical(&mut out, &root, "0", int_base)
+                }
+            });
+        }
+        node_free(Some(Box::new(root)));
+    }
+
+    if dump_env && !stats_json {
+        let (types, variables) = (&context.types, &context.variables);
+        check_written(writeln!(out, "TYPES:"));
+        check_written(print_environment(&mut out, types, sort_env));
+        check_written(writeln!(out, "VARIABLES:"));
+        check_written(print_environment(&mut out, variables, sort_env));
+    }
+
+    if stats_json && !failed {
+        check_written(print_stats_json(&mut out, &stats));
+    }
+    check_written(out.flush());
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        print_usage(&args[0]);
-        return;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Lex all of SOURCE with CONTEXT, returning the tokens as text.
+    fn lex_strings(context: &ParsingContext, source: &str) -> (Vec<String>, Error) {
+        let mut tokens = Vec::new();
+        let err = lex_tokens(context, source.as_bytes(), &mut tokens);
+        let strings = tokens
+            .iter()
+            .map(|t| source[t.beginning..t.end].to_string())
+            .collect();
+        (strings, err)
     }
 
-    let path = &args[1];
-    let contents = file_contents(path);
+    /// Parse all of SOURCE with CONTEXT into a PROGRAM node.
+    fn parse_with(context: &mut ParsingContext, source: &str) -> (Box<Node>, Error) {
+        let mut program = node_allocate();
+        let mut end = 0usize;
+        let err = parse_expr(context, source.as_bytes(), &mut end, &mut program);
+        (program, err)
+    }
 
-    if let Some(contents) = contents {
-        //printf("Contents of %s:\n---\n\"%s\"\n---\n", path, contents);
+    fn parse(source: &str) -> (Box<Node>, Error) {
+        parse_with(&mut parse_context_create(), source)
+    }
 
-        // TODO: Create API to heap allocate a program node, as well as add
-        // expressions as children.
+    /// The children of NODE, in order.
+    fn children(node: &Node) -> Vec<&Node> {
+        let mut children = Vec::new();
+        let mut child = node.children.as_deref();
+        while let Some(child_node) = child {
+            children.push(child_node);
+            child = child_node.next_child.as_deref();
+        }
+        children
+    }
+
+    #[test]
+    fn over_long_token_aborts_lexing() {
+        let mut context = parse_context_create();
+        context.max_token_length = 3;
+        let mut count = 0usize;
+        let err = count_tokens(&context, b"ab aaaaaaaaaa", &mut count);
+        assert_eq!(err.type_, ErrorType::ERROR_SYNTAX);
+        assert_eq!(err.msg.as_deref(), Some("Token too long"));
+        let span = err.span.unwrap();
+        assert_eq!((span.beginning, span.line, span.column), (3, 1, 4));
+
+        let (tokens, err) = lex_strings(&context, "abc |a|");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert_eq!(tokens, ["abc", "|a|"]);
+        let (_, err) = lex_strings(&context, "|abcd|");
+        assert_eq!(err.msg.as_deref(), Some("Token too long"));
+    }
+
+    #[test]
+    fn pipe_escaped_symbol_keeps_delimiters() {
+        let context = parse_context_create();
+        let (tokens, err) = lex_strings(&context, "|a, b| c");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert_eq!(tokens, ["|a, b|", "c"]);
+        assert_eq!(symbol_name(&tokens[0]), "a, b");
+
+        let (program, err) = parse("|a, b| : integer");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        let declaration = children(&program)[0];
+        assert_eq!(children(declaration)[1].to_string(), "a, b");
+    }
+
+    #[test]
+    fn unterminated_pipe_is_a_lexical_error_at_the_pipe() {
+        let context = parse_context_create();
+        let (tokens, err) = lex_strings(&context, "a\n  |oops");
+        assert_eq!(tokens, ["a"]);
+        assert_eq!(err.type_, ErrorType::ERROR_LEX);
+        assert_eq!(err.msg.as_deref(), Some("Unterminated pipe-escaped symbol"));
+        let span = err.span.unwrap();
+        assert_eq!((span.beginning, span.line, span.column), (4, 2, 3));
+    }
+
+    #[test]
+    fn empty_pipe_is_a_lexical_error_over_the_pipes() {
+        let context = parse_context_create();
+        let (tokens, err) = lex_strings(&context, "a ||b");
+        assert_eq!(tokens, ["a"]);
+        assert_eq!(err.type_, ErrorType::ERROR_LEX);
+        assert_eq!(err.msg.as_deref(), Some("Empty pipe-escaped symbol"));
+        let span = err.span.unwrap();
+        assert_eq!((span.beginning, span.end), (2, 4));
+        let (_, err) = parse("||");
+        assert_eq!(err.type_, ErrorType::ERROR_LEX);
+    }
+
+    #[test]
+    fn lexical_and_syntax_errors_are_told_apart() {
+        let (_, err) = parse("1 |abc");
+        assert_eq!(err.type_, ErrorType::ERROR_LEX);
+        assert_eq!(err.type_.describe(), "Lexical error");
+
+        let (_, err) = parse("1 )");
+        assert_eq!(err.type_, ErrorType::ERROR_SYNTAX);
+        assert_eq!(err.type_.describe(), "Syntax error");
+    }
+
+    #[test]
+    fn parse_one_walks_the_source_one_expression_at_a_time() {
+        let mut context = parse_context_create();
+        let source = b"1 2";
+        let mut end = 0usize;
+        let mut first = node_allocate();
+        let err = parse_one(&mut context, source, &mut end, &mut first);
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert_eq!(first.to_string(), "1");
+        let mut second = node_allocate();
+        let err = parse_one(&mut context, source, &mut end, &mut second);
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert_eq!(second.to_string(), "2");
+        let mut rest = node_allocate();
+        let err = parse_one(&mut context, source, &mut end, &mut rest);
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert!(nonep(&rest));
+    }
+
+    #[test]
+    fn trailing_symbol_is_not_dropped() {
+        let (program, err) = parse("1 foo");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        let expressions = children(&program);
+        assert_eq!(expressions.len(), 2);
+        assert!(symbolp(expressions[1]));
+        assert_eq!(expressions[1].to_string(), "foo");
+
+        let (program, _) = parse("|a, b|\n");
+        assert_eq!(children(&program)[0].to_string(), "a, b");
+    }
+
+    #[test]
+    fn copied_token_restores_the_lexer() {
+        let context = parse_context_create();
+        let source = b"a b c";
+        let mut token = Token {
+            beginning: 0,
+            end: 0,
+        };
+        let checkpoint = token;
+        let mut lexed = Vec::new();
+        for _ in 0..2 {
+            lex(&context, source, token.end, &mut token);
+            lexed.push(token);
+        }
+        token = checkpoint;
+        for expected in lexed {
+            lex(&context, source, token.end, &mut token);
+            assert_eq!(token.beginning, expected.beginning);
+            assert_eq!(token.end, expected.end);
+        }
+    }
+
+    #[test]
+    fn lexical_error_after_integer_keeps_the_integer() {
+        let (program, err) = parse("1 |abc");
+        assert_eq!(err.type_, ErrorType::ERROR_LEX);
+        let expressions = children(&program);
+        assert_eq!(expressions.len(), 1);
+        assert_eq!(expressions[0].to_string(), "1");
+    }
+
+    /// Compare lexing a large file memory-mapped against reading it into
+    /// a buffer. Run with:
+    /// cargo test --release --features mmap -- --ignored --nocapture
+    #[cfg(all(unix, feature = "mmap"))]
+    #[test]
+    #[ignore]
+    fn benchmark_mmap_against_read() {
+        let path = std::env::temp_dir().join("ndc-benchmark.nd");
+        std::fs::write(&path, "abc : integer 12345 |x, y|\n".repeat(1 << 21)).unwrap();
+        let path = path.to_str().unwrap();
+        let context = parse_context_create();
+        let mut count = 0usize;
+
+        let start = Instant::now();
+        let mapping = file_map(path).unwrap();
+        count_tokens(&context, mapping.bytes(), &mut count);
+        println!("mmap:        {:?} for {} tokens", start.elapsed(), count);
+
+        let start = Instant::now();
+        let contents = file_contents(path).unwrap();
+        count_tokens(&context, &contents, &mut count);
+        println!("read_to_end: {:?} for {} tokens", start.elapsed(), count);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn parse_all_recovers_on_the_next_line() {
         let mut context = parse_context_create();
         let mut program = node_allocate();
-        program.type_ = NodeType::NODE_TYPE_PROGRAM;
-        let mut expression = node_allocate();
-        *expression = Node {
-            type_: NodeType::NODE_TYPE_NONE,
-            value: NodeValue {
-                integer: 0,
-                symbol: None,
-            },
-            children: None,
-            next_child: None,
+        let mut errors = Vec::new();
+        let omitted = parse_all(&mut context, b"1\n) 2\n3\n", &mut program, &mut errors);
+        assert_eq!(omitted, 0);
+        let types: Vec<NodeType> = children(&program).iter().map(|n| n.type_).collect();
+        assert_eq!(
+            types,
+            [
+                NodeType::NODE_TYPE_INTEGER,
+                NodeType::NODE_TYPE_ERROR,
+                NodeType::NODE_TYPE_INTEGER
+            ]
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].type_, ErrorType::ERROR_SYNTAX);
+    }
+
+    #[test]
+    fn renderers_write_to_the_given_writer() {
+        let context = parse_context_create();
+        let mut trace = Vec::new();
+        trace_tokens(&mut trace, &context, b"a\n  1").unwrap();
+        assert_eq!(trace, b"lexed 1:1: a\nlexed 2:3: 1\n");
+
+        let (program, _) = parse("1");
+        let mut tree = Vec::new();
+        let base = IntegerBase::INTEGER_BASE_DECIMAL;
+        print_node(&mut tree, Some(&program), 0, base).unwrap();
+        assert_eq!(tree, b"PROGRAM\n    INT:1\n");
+    }
+
+    #[test]
+    fn every_error_type_but_none_and_generic_describes_itself() {
+        let types = [
+            ErrorType::ERROR_NONE,
+            ErrorType::ERROR_ARGUMENTS,
+            ErrorType::ERROR_TYPE,
+            ErrorType::ERROR_GENERIC,
+            ErrorType::ERROR_LEX,
+            ErrorType::ERROR_SYNTAX,
+            ErrorType::ERROR_TODO,
+        ];
+        for type_ in types {
+            let bare = matches!(type_, ErrorType::ERROR_NONE | ErrorType::ERROR_GENERIC);
+            assert_eq!(type_.describe().is_empty(), bare, "{:?}", type_);
+            assert!(!type_.name().is_empty());
+        }
+    }
+
+    #[test]
+    fn strict_mode_rejects_a_bare_integer_at_its_token() {
+        let mut context = parse_context_create();
+        context.strict = true;
+        let (_, err) = parse_with(&mut context, "a : integer\n  42\n");
+        assert_eq!(err.type_, ErrorType::ERROR_SYNTAX);
+        let span = err.span.unwrap();
+        assert_eq!((span.beginning, span.end), (14, 16));
+        assert_eq!((span.line, span.column), (2, 3));
+
+        let mut single = node_allocate();
+        let err = parse_single(&mut context, b" 7", &mut single);
+        assert_eq!(err.type_, ErrorType::ERROR_SYNTAX);
+        assert_eq!(err.span.unwrap().beginning, 1);
+    }
+
+    #[test]
+    fn strict_mode_accepts_declarations() {
+        let mut context = parse_context_create();
+        context.strict = true;
+        let (_, err) = parse_with(&mut context, "a : integer\n");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        let mut single = node_allocate();
+        let err = parse_single(&mut context, b"b : integer", &mut single);
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+    }
+
+    #[test]
+    fn empty_source_is_an_empty_program() {
+        for source in ["", "   \n\t", "#!/usr/bin/env ndc\n"] {
+            let (program, err) = parse(source);
+            assert_eq!(err.type_, ErrorType::ERROR_NONE);
+            assert_eq!(program.type_, NodeType::NODE_TYPE_PROGRAM);
+            assert!(children(&program).is_empty());
+        }
+    }
+
+    #[test]
+    fn huge_digit_run_is_rejected_quickly() {
+        let digits = "9".repeat(10 << 20);
+        let start = std::time::Instant::now();
+
+        let (_, err) = parse(&digits);
+        assert_eq!(err.type_, ErrorType::ERROR_SYNTAX);
+        assert_eq!(err.msg.as_deref(), Some("Token too long"));
+
+        let mut context = parse_context_create();
+        context.max_token_length = digits.len();
+        let (_, err) = parse_with(&mut context, &digits);
+        assert_eq!(err.type_, ErrorType::ERROR_SYNTAX);
+        assert_eq!(
+            err.msg.as_deref(),
+            Some("Integer literal too large for integer type")
+        );
+        let span = err.span.unwrap();
+        assert_eq!((span.beginning, span.end), (0, digits.len()));
+
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+    }
+
+    #[test]
+    fn error_json_always_has_every_key() {
+        let mut err = ok();
+        error_prep(&mut err, ErrorType::ERROR_TYPE, "say \"hi\"");
+        assert_eq!(
+            error_json(&err, None),
+            "{\"kind\":\"TYPE\",\"message\":\"say \\\"hi\\\"\",\"line\":null,\"column\":null,\"span\":null,\"file\":null}"
+        );
+        let (_, err) = parse("\n  )");
+        assert_eq!(
+            error_json(&err, Some("a.nd")),
+            "{\"kind\":\"SYNTAX\",\"message\":\"Unexpected ')'\",\"line\":2,\"column\":3,\"span\":[3,4],\"file\":\"a.nd\"}"
+        );
+    }
+
+    #[test]
+    fn rationals_are_kept_in_lowest_terms() {
+        let value = |numerator, denominator| {
+            node_rational(numerator, denominator).map(|r| (r.value.integer, r.value.denominator))
+        };
+        assert_eq!(value(6, 8), Some((3, 4)));
+        assert_eq!(value(6, -8), Some((-3, 4)));
+        assert_eq!(value(1, 0), None);
+        assert_eq!(value(0, integer_t::MIN), Some((0, 1)));
+        assert_eq!(value(0, -5), Some((0, 1)));
+        assert_eq!(value(integer_t::MIN, integer_t::MIN), Some((1, 1)));
+        assert_eq!(value(1, integer_t::MIN), None);
+
+        let (program, err) = parse("6/8 0/-9223372036854775808");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        let rendered: Vec<String> = children(&program).iter().map(|n| n.to_string()).collect();
+        assert_eq!(rendered, ["3/4", "0/1"]);
+        let (_, err) = parse("1/0");
+        assert_eq!(
+            err.msg.as_deref(),
+            Some("Rational literal has a zero denominator")
+        );
+    }
+
+    #[test]
+    fn overflowing_literals_follow_the_policy() {
+        let value = |policy, source: &str| {
+            let mut context = parse_context_create();
+            context.overflow_policy = policy;
+            let (program, err) = parse_with(&mut context, source);
+            let values: Vec<integer_t> =
+                children(&program).iter().map(|n| n.value.integer).collect();
+            (err.type_, values)
+        };
+        let overflowing = "9223372036854775808 -9223372036854775809 18446744073709551617";
+
+        let (type_, _) = value(OverflowPolicy::OVERFLOW_POLICY_CHECKED, overflowing);
+        assert_eq!(type_, ErrorType::ERROR_SYNTAX);
+        let (type_, values) = value(OverflowPolicy::OVERFLOW_POLICY_WRAPPING, overflowing);
+        assert_eq!(type_, ErrorType::ERROR_NONE);
+        assert_eq!(values, [integer_t::MIN, integer_t::MAX, 1]);
+        let (type_, values) = value(OverflowPolicy::OVERFLOW_POLICY_SATURATING, overflowing);
+        assert_eq!(type_, ErrorType::ERROR_NONE);
+        assert_eq!(values, [integer_t::MAX, integer_t::MIN, integer_t::MAX]);
+
+        let largest = "9223372036854775807";
+        let (type_, values) = value(OverflowPolicy::OVERFLOW_POLICY_CHECKED, largest);
+        assert_eq!(type_, ErrorType::ERROR_NONE);
+        assert_eq!(values, [integer_t::MAX]);
+    }
+
+    #[test]
+    fn literal_nodes_display_their_value() {
+        assert_eq!(node_allocate().to_string(), "nil");
+        let mut integer = node_allocate();
+        integer.type_ = NodeType::NODE_TYPE_INTEGER;
+        integer.value.integer = -42;
+        assert_eq!(integer.to_string(), "-42");
+        assert_eq!(node_rational(-3, 4).unwrap().to_string(), "-3/4");
+        assert_eq!(node_symbol("foo").to_string(), "foo");
+        assert_eq!(node_symbol("a, b").to_string(), "a, b");
+
+        let (program, _) = parse("a : integer");
+        assert_eq!(program.to_string(), "");
+        assert_eq!(children(&program)[0].to_string(), "");
+    }
+
+    #[test]
+    fn offsets_convert_to_lines_and_columns() {
+        let source = b"ab c\nde";
+        assert_eq!(offset_to_line_col(source, 0, 1), (1, 1));
+        assert_eq!(offset_to_line_col(source, 3, 1), (1, 4));
+        assert_eq!(offset_to_line_col(source, 4, 1), (1, 5));
+        assert_eq!(offset_to_line_col(source, 5, 1), (2, 1));
+        assert_eq!(offset_to_line_col(source, 6, 1), (2, 2));
+        assert_eq!(offset_to_line_col(source, source.len(), 1), (2, 3));
+        assert_eq!(offset_to_line_col(source, source.len() + 5, 1), (2, 3));
+        assert_eq!(offset_to_line_col(b"", 0, 1), (1, 1));
+    }
+
+    #[test]
+    fn columns_count_characters_not_bytes() {
+        let source = "café x";
+        let mut token = Token {
+            beginning: 0,
+            end: 0,
+        };
+        let context = parse_context_create();
+        lex(&context, source.as_bytes(), 0, &mut token);
+        assert_eq!(&source[token.beginning..token.end], "café");
+        lex(&context, source.as_bytes(), token.end, &mut token);
+        assert_eq!(token.beginning, 6);
+        let span = span_create(source.as_bytes(), &token, 1);
+        assert_eq!((span.line, span.column), (1, 6));
+    }
+
+    #[test]
+    fn only_a_leading_shebang_is_skipped() {
+        let (program, err) = parse("#!/usr/bin/env ndc\n1");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        let expressions = children(&program);
+        assert_eq!(expressions.len(), 1);
+        assert_eq!(expressions[0].to_string(), "1");
+
+        let context = parse_context_create();
+        let (tokens, err) = lex_strings(&context, "1\n#!x\n");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert_eq!(tokens, ["1", "#!x"]);
+        let (program, err) = parse("1\n#!x\n");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        let expressions = children(&program);
+        assert_eq!(expressions[1].type_, NodeType::NODE_TYPE_SYMBOL);
+        assert_eq!(expressions[1].to_string(), "#!x");
+    }
+
+    #[test]
+    fn invalid_utf8_in_a_token_is_an_error() {
+        let source = b"1 a\xff b";
+        let mut text = "";
+        let token = Token {
+            beginning: 2,
+            end: 4,
+        };
+        let err = token_text(source, &token, &mut text);
+        assert_eq!(err.type_, ErrorType::ERROR_SYNTAX);
+        assert_eq!(err.msg.as_deref(), Some("Invalid UTF-8 in token"));
+
+        let mut context = parse_context_create();
+        let mut program = node_allocate();
+        let mut end = 0usize;
+        let err = parse_expr(&mut context, source, &mut end, &mut program);
+        assert_eq!(err.type_, ErrorType::ERROR_SYNTAX);
+        assert_eq!(err.msg.as_deref(), Some("Invalid UTF-8 in token"));
+        assert_eq!(err.span.unwrap().beginning, 2);
+    }
+
+    #[test]
+    fn dot_labels_escape_only_what_dot_understands() {
+        assert_eq!(dot_escaped("a\"b\\c\nd\te"), "a\\\"b\\\\c\\nde");
+        let mut dot = Vec::new();
+        let base = IntegerBase::INTEGER_BASE_DECIMAL;
+        print_node_dot(&mut dot, &node_symbol("x\ty"), base).unwrap();
+        assert_eq!(dot, b"digraph AST {\n    n0 [label=\"SYMBOL xy\"];\n}\n");
+        assert_eq!(escaped("a\"b\tc"), "a\\\"b\\u0009c");
+    }
+
+    #[test]
+    fn redefinition_is_allowed_unless_forbidden() {
+        let source = "a : integer\nb : integer\n  a : integer\n";
+        let (program, err) = parse(source);
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert_eq!(children(&program).len(), 3);
+
+        let mut context = parse_context_create();
+        context.forbid_redefinition = true;
+        let (program, err) = parse_with(&mut context, source);
+        assert_eq!(err.type_, ErrorType::ERROR_GENERIC);
+        assert_eq!(err.msg.as_deref(), Some("Redefinition of a"));
+        let span = err.span.unwrap();
+        assert_eq!((span.line, span.column), (3, 3));
+        assert_eq!(children(&program).len(), 2);
+    }
+
+    #[test]
+    fn spans_carry_range_and_position() {
+        let source = b"ab\n\tcd ef";
+        let token = Token {
+            beginning: 4,
+            end: 6,
+        };
+        let span = span_create(source, &token, 1);
+        assert_eq!(
+            span,
+            Span {
+                beginning: 4,
+                end: 6,
+                line: 2,
+                column: 2,
+            }
+        );
+        assert_eq!(&source[span.beginning..span.end], b"cd");
+        assert_eq!(span_create(source, &token, 8).column, 9);
+
+        let mut err = ok();
+        assert!(err.span.is_none());
+        error_prep_at(&mut err, ErrorType::ERROR_SYNTAX, "Bad token", span);
+        assert_eq!(err.span, Some(span));
+        assert_eq!(err.msg.as_deref(), Some("Bad token"));
+    }
+
+    #[test]
+    fn latin1_transcodes_each_byte_to_one_character() {
+        assert_eq!(latin1_to_utf8(b"caf\xe9"), "café".as_bytes());
+        assert_eq!(latin1_to_utf8(b"\xff\x00a"), "ÿ\0a".as_bytes());
+    }
+
+    #[test]
+    fn count_tokens_counts_every_token() {
+        let context = parse_context_create();
+        let mut count = 0usize;
+        let err = count_tokens(&context, b"a : integer\n1 |b c| 3/4\n", &mut count);
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert_eq!(count, 6);
+        let err = count_tokens(&context, b"", &mut count);
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    fn annotated_declarations_need_a_type_name() {
+        let (program, err) = parse("x : integer");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        let declaration = children(&program)[0];
+        assert_eq!(declaration.type_, NodeType::NODE_TYPE_VARIABLE_DECLARATION);
+        let parts = children(declaration);
+        assert_eq!(parts[0].type_, NodeType::NODE_TYPE_INTEGER);
+        assert_eq!(parts[1].to_string(), "x");
+
+        for source in ["x :", "x : )"] {
+            let (_, err) = parse(source);
+            assert_eq!(err.type_, ErrorType::ERROR_SYNTAX, "{}", source);
+            assert_eq!(err.msg.as_deref(), Some("Expected a type name after ':'"));
+            assert_eq!(err.span.unwrap().beginning, 2);
+        }
+    }
+
+    #[test]
+    fn environments_print_types_in_either_order() {
+        let mut context = parse_context_create();
+        let (_, err) = parse_with(&mut context, "b : integer\nc : integer\na : integer\n");
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+
+        let mut stored = Vec::new();
+        print_environment(&mut stored, &context.variables, false).unwrap();
+        assert_eq!(
+            String::from_utf8(stored).unwrap(),
+            "[0] a: INTEGER\n[0] c: INTEGER\n[0] b: INTEGER\n"
+        );
+        let mut sorted = Vec::new();
+        print_environment(&mut sorted, &context.variables, true).unwrap();
+        assert_eq!(
+            String::from_utf8(sorted).unwrap(),
+            "[0] a: INTEGER\n[0] b: INTEGER\n[0] c: INTEGER\n"
+        );
+    }
+
+    #[test]
+    fn expressions_can_not_start_with_a_closer_or_separator() {
+        for (source, message, offset) in [
+            (")", "Unexpected ')'", 0),
+            (", 1", "Unexpected ','", 0),
+            ("  : integer", "Unexpected ':'", 2),
+        ] {
+            let (_, err) = parse(source);
+            assert_eq!(err.type_, ErrorType::ERROR_SYNTAX);
+            assert_eq!(err.msg.as_deref(), Some(message));
+            let span = err.span.unwrap();
+            assert_eq!((span.beginning, span.end), (offset, offset + 1));
+        }
+    }
+
+    #[test]
+    fn tabs_advance_to_the_next_tab_stop() {
+        let mut context = parse_context_create();
+        context.tab_width = 4;
+        let (_, err) = parse_with(&mut context, "\t)");
+        assert_eq!(err.span.unwrap().column, 5);
+        let (_, err) = parse_with(&mut context, "ab\t)");
+        assert_eq!(err.span.unwrap().column, 5);
+        let (_, err) = parse_with(&mut context, "abcd\t)");
+        assert_eq!(err.span.unwrap().column, 9);
+        let (_, err) = parse("\t)");
+        assert_eq!(err.span.unwrap().column, 2);
+    }
+
+    #[test]
+    fn file_size_measures_and_restores_the_position() {
+        let mut cursor = io::Cursor::new(vec![0u8; 42]);
+        cursor.set_position(7);
+        assert_eq!(file_size(&mut cursor), 42);
+        assert_eq!(cursor.position(), 7);
+
+        /// A stream that claims to end past anything usize can address.
+        struct Huge(u64);
+        impl Seek for Huge {
+            fn seek(&mut self, from: SeekFrom) -> io::Result<u64> {
+                self.0 = match from {
+                    SeekFrom::Start(offset) => offset,
+                    SeekFrom::End(_) => u64::MAX,
+                    SeekFrom::Current(_) => self.0,
+                };
+                Ok(self.0)
+            }
+        }
+        let mut huge = Huge(0);
+        let size = file_size(&mut huge);
+        assert_eq!(size, u64::MAX);
+        assert_eq!(huge.0, 0);
+        assert_eq!(contents_buffer_size(size), None);
+    }
+
+    #[test]
+    fn buffer_size_refuses_sizes_past_usize() {
+        assert_eq!(contents_buffer_size(0), Some(1));
+        assert_eq!(contents_buffer_size(41), Some(42));
+        assert_eq!(contents_buffer_size(u64::MAX), None);
+        assert_eq!(contents_buffer_size(usize::MAX as u64), None);
+    }
+
+    #[test]
+    fn single_expression_must_end_the_source() {
+        let mut context = parse_context_create();
+        let mut result = node_allocate();
+        let err = parse_single(&mut context, b"42 ", &mut result);
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert_eq!(result.to_string(), "42");
+
+        let mut result = node_allocate();
+        let err = parse_single(&mut context, b"42 foo", &mut result);
+        assert_eq!(err.type_, ErrorType::ERROR_SYNTAX);
+        assert_eq!(err.msg.as_deref(), Some("Unexpected trailing tokens"));
+        let span = err.span.unwrap();
+        assert_eq!((span.beginning, span.end), (3, 6));
+
+        let mut result = node_allocate();
+        let err = parse_single(&mut context, b"  ", &mut result);
+        assert_eq!(err.msg.as_deref(), Some("Expected an expression"));
+    }
+
+    #[test]
+    fn merging_programs_flattens_their_children() {
+        let (first, _) = parse("1 2");
+        let (second, _) = parse("3 4 5");
+        let mut merged = node_allocate();
+        let err = merge_programs(vec![*first, *second], &mut merged);
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+        assert_eq!(merged.type_, NodeType::NODE_TYPE_PROGRAM);
+        let values: Vec<String> = children(&merged).iter().map(|n| n.to_string()).collect();
+        assert_eq!(values, ["1", "2", "3", "4", "5"]);
+
+        let (program, _) = parse("1");
+        let mut merged = node_allocate();
+        let err = merge_programs(vec![*program, *node_symbol("x")], &mut merged);
+        assert_eq!(err.type_, ErrorType::ERROR_TYPE);
+        assert_eq!(children(&merged).len(), 1);
+    }
+
+    #[test]
+    fn changing_one_integer_changes_one_canonical_line() {
+        let canonical = |source: &str| {
+            let (program, err) = parse(source);
+            assert_eq!(err.type_, ErrorType::ERROR_NONE);
+            let mut out = Vec::new();
+            let base = IntegerBase::INTEGER_BASE_DECIMAL;
+            print_node_canonical(&mut out, &program, "0", base).unwrap();
+            String::from_utf8(out).unwrap()
         };
-        let mut contents_it = 0usize;
-        let err = parse_expr(&mut context, &contents, &mut contents_it, &mut expression);
-        node_add_child(&mut program, expression);
+        let mut source = String::new();
+        for i in 0..100 {
+            source.push_str(&format!("v{} : integer\n{} {}/7\n", i, i, i + 1));
+        }
+        let before = canonical(&source);
+        let after = canonical(&source.replace("\n50 ", "\n51 "));
+        assert_eq!(before.lines().count(), after.lines().count());
+        let changed: Vec<(&str, &str)> = before
+            .lines()
+            .zip(after.lines())
+            .filter(|(a, b)| a != b)
+            .collect();
+        assert_eq!(changed, [("0.151 INTEGER 50", "0.151 INTEGER 51")]);
+    }
+
+    #[test]
+    fn depth_limit_catches_a_deep_tree() {
+        let mut root = node_allocate();
+        for _ in 0..999 {
+            let mut parent = node_allocate();
+            parent.type_ = NodeType::NODE_TYPE_PROGRAM;
+            node_add_child(&mut parent, root);
+            root = parent;
+        }
+        assert_eq!(check_ast_depth(&root, 1000).type_, ErrorType::ERROR_NONE);
+        let err = check_ast_depth(&root, 999);
+        assert_eq!(err.type_, ErrorType::ERROR_GENERIC);
+        assert_eq!(
+            err.msg.as_deref(),
+            Some("AST is 1000 levels deep, over the limit of 999")
+        );
+    }
+
+    #[test]
+    fn integers_render_in_the_chosen_base() {
+        let integer = |value| {
+            let mut node = node_allocate();
+            node.type_ = NodeType::NODE_TYPE_INTEGER;
+            node.value.integer = value;
+            node
+        };
+        let render = |value, base| node_value_string(&integer(value), base);
+        assert_eq!(render(255, IntegerBase::INTEGER_BASE_HEX), "0xff");
+        assert_eq!(render(255, IntegerBase::INTEGER_BASE_BINARY), "0b11111111");
+        assert_eq!(render(255, IntegerBase::INTEGER_BASE_OCTAL), "0o377");
+        assert_eq!(render(255, IntegerBase::INTEGER_BASE_DECIMAL), "255");
+        assert_eq!(render(-255, IntegerBase::INTEGER_BASE_HEX), "-0xff");
+        assert_eq!(
+            render(integer_t::MIN, IntegerBase::INTEGER_BASE_HEX),
+            "-0x8000000000000000"
+        );
+        let rational = node_rational(3, 4).unwrap();
+        let base = IntegerBase::INTEGER_BASE_HEX;
+        assert_eq!(node_value_string(&rational, base), "3/4");
+    }
+
+    #[test]
+    fn token_coverage_catches_corrupted_tokens() {
+        let context = parse_context_create();
+        let source = b"#!ndc\na : integer\n  |b c| 3/4\n";
+        let mut tokens = Vec::new();
+        let err = lex_tokens(&context, source, &mut tokens);
+        assert_eq!(err.type_, ErrorType::ERROR_NONE);
+
+        let message = |tokens: &[Token]| verify_token_coverage(source, tokens).msg;
+        assert_eq!(message(&tokens), None);
+        let dropped = [&tokens[..1], &tokens[2..]].concat();
+        assert_eq!(
+            message(&dropped).as_deref(),
+            Some("Byte 8 is not covered by any token")
+        );
+        let mut overlapping = tokens.clone();
+        overlapping[1].beginning -= 2;
+        assert_eq!(
+            message(&overlapping).as_deref(),
+            Some("Token 1 at byte 6 overlaps what came before it")
+        );
+        let mut empty = tokens.clone();
+        empty[2].end = empty[2].beginning;
+        assert_eq!(
+            message(&empty).as_deref(),
+            Some("Token 2 is empty or out of bounds")
+        );
+        let truncated = &tokens[..tokens.len() - 1];
+        assert!(message(truncated).is_some());
+    }
 
-        print_error(&err);
+    #[test]
+    fn overflow_policy_applies_only_to_digit_runs() {
+        for policy in [
+            OverflowPolicy::OVERFLOW_POLICY_CHECKED,
+            OverflowPolicy::OVERFLOW_POLICY_WRAPPING,
+            OverflowPolicy::OVERFLOW_POLICY_SATURATING,
+        ] {
+            let mut context = parse_context_create();
+            context.overflow_policy = policy;
+            let (program, err) = parse_with(&mut context, "99999999999999999999x");
+            assert_eq!(err.type_, ErrorType::ERROR_NONE, "{:?}", policy);
+            let expression = children(&program)[0];
+            assert_eq!(expression.type_, NodeType::NODE_TYPE_SYMBOL, "{:?}", policy);
+            assert_eq!(expression.to_string(), "99999999999999999999x");
+        }
+
+        let rational = |policy| {
+            let mut context = parse_context_create();
+            context.overflow_policy = policy;
+            let (program, err) = parse_with(&mut context, "9223372036854775808/2");
+            let rendered: Vec<String> = children(&program).iter().map(|n| n.to_string()).collect();
+            (err.msg, rendered)
+        };
+        let (msg, _) = rational(OverflowPolicy::OVERFLOW_POLICY_CHECKED);
+        assert_eq!(
+            msg.as_deref(),
+            Some("Rational literal too large for integer type")
+        );
+        let (msg, rendered) = rational(OverflowPolicy::OVERFLOW_POLICY_WRAPPING);
+        assert_eq!(msg, None);
+        assert_eq!(rendered, ["-4611686018427387904/1"]);
+        let (msg, rendered) = rational(OverflowPolicy::OVERFLOW_POLICY_SATURATING);
+        assert_eq!(msg, None);
+        assert_eq!(rendered, ["9223372036854775807/2"]);
+    }
+
+    #[test]
+    fn parse_all_keeps_at_most_max_errors() {
+        let mut context = parse_context_create();
+        context.max_errors = 2;
+        let mut program = node_allocate();
+        let mut errors = Vec::new();
+        let source = b")\n,\n1\n)\n)\n:\n";
+        let omitted = parse_all(&mut context, source, &mut program, &mut errors);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(omitted, 3);
+        assert_eq!(errors[1].msg.as_deref(), Some("Unexpected ','"));
+        let holes = children(&program)
+            .iter()
+            .filter(|n| n.type_ == NodeType::NODE_TYPE_ERROR)
+            .count();
+        assert_eq!(holes, 5);
+    }
+
+    #[test]
+    fn errors_sort_into_source_order() {
+        let error = |message: &str, beginning: Option<usize>| {
+            let mut err = ok();
+            error_prep(&mut err, ErrorType::ERROR_SYNTAX, message);
+            err.span = beginning.map(|beginning| Span {
+                beginning,
+                end: beginning + 1,
+                line: 1,
+                column: beginning + 1,
+            });
+            err
+        };
+        let mut errors = vec![
+            error("depth", None),
+            error("late", Some(9)),
+            error("early", Some(2)),
+            error("also early", Some(2)),
+            error("doubt", None),
+        ];
+        sort_errors(&mut errors);
+        let messages: Vec<&str> = errors.iter().map(|e| e.msg.as_deref().unwrap()).collect();
+        assert_eq!(messages, ["early", "also early", "late", "depth", "doubt"]);
+    }
 
-        print_node(Some(&program), 0);
-        println!();
+    #[test]
+    fn first_error_span_is_the_earliest() {
+        let (_, late) = parse("1\n  )");
+        let (_, early) = parse(" ,");
+        let mut spanless = ok();
+        error_prep(&mut spanless, ErrorType::ERROR_GENERIC, "no position");
+        let errors = [spanless.clone(), late.clone(), early.clone()];
+        assert_eq!(first_error_span(&errors), early.span);
+        let errors = [early.clone(), spanless.clone(), late];
+        assert_eq!(first_error_span(&errors), early.span);
+        assert_eq!(first_error_span(&[spanless]), None);
+        assert_eq!(first_error_span(&[]), None);
+    }
 
-        node_free(Some(program));
+    #[test]
+    fn shadowed_bindings_list_inner_scope_first() {
+        let mut parent = environment_create(None);
+        environment_set(&mut parent, node_symbol("b"), node_integer(0));
+        environment_set(&mut parent, node_symbol("a"), node_integer(0));
+        let mut child = environment_create(Some(parent));
+        environment_set(&mut child, node_symbol("a"), node_symbol("x"));
+        environment_set(&mut child, node_symbol("c"), node_symbol("x"));
+
+        let mut stored = Vec::new();
+        print_environment(&mut stored, &child, false).unwrap();
+        assert_eq!(
+            String::from_utf8(stored).unwrap(),
+            "[0] c: SYMBOL\n[0] a: SYMBOL\n[1] a: INTEGER\n[1] b: INTEGER\n"
+        );
+        let mut sorted = Vec::new();
+        print_environment(&mut sorted, &child, true).unwrap();
+        assert_eq!(
+            String::from_utf8(sorted).unwrap(),
+            "[0] a: SYMBOL\n[1] a: INTEGER\n[1] b: INTEGER\n[0] c: SYMBOL\n"
+        );
     }
 }
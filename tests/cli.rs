@@ -0,0 +1,200 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+/// Write CONTENTS to a file named after NAME in the temporary directory.
+fn source_file(name: &str, contents: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("ndc-{}-{}.nd", std::process::id(), name));
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Run the compiler with ARGS.
+fn ndc(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_ndc"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn check_exits_zero_when_every_file_parses() {
+    let path = source_file("check-ok", b"a : integer\n1\n");
+    let output = ndc(&["--check", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn check_prints_only_errors_and_exits_one() {
+    let good = source_file("check-good", b"1\n");
+    let bad = source_file("check-bad", b"a : float\nfoo bar\n");
+    let output = ndc(&["--check", good.to_str().unwrap(), bad.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let expected = format!(
+        "{}:\n\
+         ERROR at 1:5: Mismatched types\n     : Invalid type within variable declaration\n\
+         ERROR at 2:5: Syntax error\n     : Unrecognized token\n",
+        bad.display()
+    );
+    assert_eq!(stdout(&output), expected);
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn tokens_are_traced_only_when_asked() {
+    let path = source_file("trace", b"a : integer\n");
+    let quiet = ndc(&["--check", path.to_str().unwrap()]);
+    assert!(!stderr(&quiet).contains("lexed"));
+    let traced = ndc(&["--check", "--trace-lex", path.to_str().unwrap()]);
+    assert_eq!(
+        stderr(&traced),
+        "lexed 1:1: a\nlexed 1:3: :\nlexed 1:5: integer\n"
+    );
+}
+
+#[test]
+fn output_file_holds_the_ast() {
+    let input = source_file("output-ok", b"1\n");
+    let output_path =
+        std::env::temp_dir().join(format!("ndc-{}-output-ok.out", std::process::id()));
+    let output = ndc(&["-o", output_path.to_str().unwrap(), input.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(
+        std::fs::read_to_string(&output_path).unwrap(),
+        "PROGRAM\n    INT:1\n\n"
+    );
+}
+
+#[test]
+fn unreadable_input_creates_no_output_file() {
+    let output_path =
+        std::env::temp_dir().join(format!("ndc-{}-output-bad.out", std::process::id()));
+    let _ = std::fs::remove_file(&output_path);
+    let output = ndc(&["-o", output_path.to_str().unwrap(), "/nonexistent/input.nd"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!output_path.exists());
+}
+
+#[test]
+fn empty_file_prints_an_empty_program() {
+    let path = source_file("empty", b"");
+    let output = ndc(&[path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "PROGRAM\n\n");
+    assert_eq!(stderr(&output), "");
+}
+
+#[test]
+fn stats_json_is_the_only_output() {
+    let path = source_file("stats", b"a : integer\n1 2\n");
+    let output = ndc(&["--stats-json", "--dump-env", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = stdout(&output);
+    assert!(stdout.starts_with("{\"phases_ns\":{"), "{}", stdout);
+    assert!(
+        stdout.ends_with(",\"tokens\":5,\"bytes\":16}\n"),
+        "{}",
+        stdout
+    );
+    assert_eq!(stdout.lines().count(), 1);
+    assert!(stdout.contains("\"nodes\":6,"), "{}", stdout);
+}
+
+#[test]
+fn every_dump_format_renders_the_ast() {
+    let path = source_file("formats", b"1 a\n");
+    let path = path.to_str().unwrap();
+    let formats = [
+        ("--dump-ast", "PROGRAM\n    INT:1\n    SYM:a\n\n"),
+        ("--dump-ast=tree", "PROGRAM\n    INT:1\n    SYM:a\n\n"),
+        (
+            "--dump-ast=json",
+            "{\"type\":\"PROGRAM\",\"children\":[{\"type\":\"INTEGER\",\"value\":1},\
+             {\"type\":\"SYMBOL\",\"value\":\"a\"}]}\n",
+        ),
+        (
+            "--dump-ast=dot",
+            "digraph AST {\n    n0 [label=\"PROGRAM\"];\n    n0 -> n1;\n    \
+             n1 [label=\"INTEGER 1\"];\n    n0 -> n2;\n    n2 [label=\"SYMBOL a\"];\n}\n",
+        ),
+        (
+            "--dump-ast=canonical",
+            "0 PROGRAM\n0.0 INTEGER 1\n0.1 SYMBOL \"a\"\n",
+        ),
+    ];
+    for (flag, expected) in formats {
+        let output = ndc(&[flag, path]);
+        assert_eq!(output.status.code(), Some(0), "{}", flag);
+        assert_eq!(stdout(&output), expected, "{}", flag);
+    }
+}
+
+#[test]
+fn unknown_dump_format_lists_the_valid_ones() {
+    let path = source_file("formats-bad", b"1\n");
+    let output = ndc(&["--dump-ast=xml", path.to_str().unwrap()]);
+    assert_eq!(
+        stdout(&output),
+        "ERROR: Invalid arguments\n     : --dump-ast expects one of: tree, json, dot, canonical\n"
+    );
+}
+
+#[test]
+fn latin1_source_is_read_only_when_asked() {
+    let path = source_file("latin1", b"caf\xe9\n");
+    let path = path.to_str().unwrap();
+    let output = ndc(&["--encoding", "latin1", path]);
+    assert_eq!(stdout(&output), "PROGRAM\n    SYM:café\n\n");
+    let output = ndc(&[path]);
+    assert!(stdout(&output).contains("Invalid UTF-8 in token"));
+}
+
+#[test]
+fn count_tokens_prints_one_count_per_file() {
+    let first = source_file("count-first", b"a : integer\n");
+    let second = source_file("count-second", b"1 2\n");
+    let output = ndc(&[
+        "--count-tokens",
+        first.to_str().unwrap(),
+        second.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "3\n2\n");
+}
+
+#[test]
+fn count_tokens_prints_no_count_for_a_file_that_fails_to_lex() {
+    let good = source_file("count-good", b"1 2\n");
+    let bad = source_file("count-bad", b"a |x");
+    let output = ndc(&[
+        "--count-tokens",
+        bad.to_str().unwrap(),
+        good.to_str().unwrap(),
+    ]);
+    assert_eq!(output.status.code(), Some(1));
+    let stdout = stdout(&output);
+    assert!(stdout.contains("Unterminated"), "{}", stdout);
+    assert!(stdout.ends_with("\n2\n"), "{}", stdout);
+    assert!(!stdout.contains("\n1\n"), "{}", stdout);
+}
+
+#[test]
+fn check_stops_reporting_at_max_errors() {
+    let path = source_file("max-errors", b")\n)\n1\n)\n)\n");
+    let output = ndc(&["--check", "--max-errors", "1", path.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(1));
+    let expected = format!(
+        "{}:\nERROR at 1:1: Syntax error\n     : Unexpected ')'\n... and 3 more errors\n",
+        path.display()
+    );
+    assert_eq!(stdout(&output), expected);
+}